@@ -0,0 +1,60 @@
+//! Measures the time required for the initial scan of a large directory tree.
+//!
+//! Run with `cargo bench --bench startup`. The tree is created in a temporary directory and
+//! contains `BREADTH^DEPTH` leaf directories.
+
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use futures_util::StreamExt;
+
+use fswatcher::{FileSystemEvent, FileSystemWatcherBuilder};
+
+const BREADTH: usize = 20;
+const DEPTH: usize = 3;
+const ITERATIONS: usize = 5;
+
+fn create_tree(path: &Path, depth: usize) {
+    if depth == 0 {
+        return;
+    }
+    for i in 0..BREADTH {
+        let subdir = path.join(i.to_string());
+        fs::create_dir(&subdir).unwrap();
+        create_tree(&subdir, depth - 1);
+    }
+}
+
+/// Returns the time until `ScanComplete` is received.
+async fn scan(path: &Path, watch_batch_size: usize) -> Duration {
+    let start = Instant::now();
+    let mut watcher = FileSystemWatcherBuilder::new(path.as_os_str())
+        .watch_batch_size(watch_batch_size)
+        .build()
+        .unwrap();
+    while let Some(event) = watcher.next().await {
+        if let FileSystemEvent::ScanComplete = event {
+            break;
+        }
+    }
+    start.elapsed()
+}
+
+#[tokio::main(basic_scheduler)]
+async fn main() {
+    let dir = tempfile::tempdir().unwrap();
+    create_tree(dir.path(), DEPTH);
+
+    for &watch_batch_size in &[1, 16, 256] {
+        let mut total = Duration::from_secs(0);
+        for _ in 0..ITERATIONS {
+            total += scan(dir.path(), watch_batch_size).await;
+        }
+        println!(
+            "watch_batch_size {:>4}: {:?} per scan",
+            watch_batch_size,
+            total / ITERATIONS as u32
+        );
+    }
+}
@@ -0,0 +1,147 @@
+//! Stream adapter which collects events until the file system has been quiet for some time.
+
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::stream::Stream;
+use tokio::time::{self, Delay};
+
+use super::FileSystemEvent;
+
+/// A stream which returns the events in batches once no new event has been received for a
+/// period of time.
+///
+/// In contrast to `FileEventDelay`, which delays each event individually, the batch is only
+/// returned once the activity has settled, which is useful to trigger a single action (e.g., a
+/// rebuild) for a burst of changes. A `Stopped` event ends the batch right away, as no further
+/// events will be received. See `FileSystemWatcher::batched()`.
+pub struct Batched<T>
+where
+    T: Stream<Item = FileSystemEvent>,
+{
+    input: Pin<Box<T>>,
+    quiet: Duration,
+    /// Timer which expires `quiet` after the last event has been received.
+    timer: Option<Pin<Box<Delay>>>,
+    batch: Vec<FileSystemEvent>,
+    /// Set once the input stream has ended, the input must not be polled afterwards.
+    input_finished: bool,
+}
+
+impl<T> Batched<T>
+where
+    T: Stream<Item = FileSystemEvent>,
+{
+    pub fn new(input: T, quiet: Duration) -> Self {
+        Self {
+            input: Box::pin(input),
+            quiet,
+            timer: None,
+            batch: Vec::new(),
+            input_finished: false,
+        }
+    }
+
+    fn take_batch(&mut self) -> Vec<FileSystemEvent> {
+        self.timer = None;
+        mem::replace(&mut self.batch, Vec::new())
+    }
+}
+
+impl<T> Stream for Batched<T>
+where
+    T: Stream<Item = FileSystemEvent>,
+{
+    type Item = Vec<FileSystemEvent>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        // Safe, as we will not move self_.
+        let self_ = unsafe { self.get_unchecked_mut() };
+
+        if !self_.input_finished {
+            while let Poll::Ready(event) = Pin::as_mut(&mut self_.input).poll_next(cx) {
+                match event {
+                    Some(FileSystemEvent::Stopped(reason)) => {
+                        self_.batch.push(FileSystemEvent::Stopped(reason));
+                        return Poll::Ready(Some(self_.take_batch()));
+                    }
+                    Some(event) => {
+                        // Every event restarts the quiet period.
+                        self_.batch.push(event);
+                        self_.timer = Some(Box::pin(time::delay_for(self_.quiet)));
+                    }
+                    None => {
+                        self_.input_finished = true;
+                        break;
+                    }
+                }
+            }
+        }
+
+        if self_.input_finished {
+            // The remaining events are returned without waiting for the timer.
+            if self_.batch.is_empty() {
+                return Poll::Ready(None);
+            }
+            return Poll::Ready(Some(self_.take_batch()));
+        }
+
+        // Polling the timer registers the waker, so we must not return Poll::Pending without
+        // polling the current timer.
+        let timer = match self_.timer.as_mut() {
+            Some(timer) => timer,
+            None => return Poll::Pending,
+        };
+        if Pin::as_mut(timer).poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        Poll::Ready(Some(self_.take_batch()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::stream::{self, StreamExt};
+    use tokio::time;
+
+    use super::Batched;
+    use crate::{FileSystemEvent, StopReason};
+
+    #[tokio::test]
+    async fn events_are_returned_once_the_input_is_quiet() {
+        let quiet = Duration::from_millis(50);
+        let start = time::Instant::now();
+        let input = stream::iter(vec![
+            FileSystemEvent::FileCreated("/a".into(), None),
+            FileSystemEvent::FileModified("/a".into(), None),
+        ])
+        .chain(stream::pending());
+        let mut batched = Batched::new(input, quiet);
+        let batch = time::timeout(Duration::from_secs(1), batched.next()).await;
+        assert!(start.elapsed() >= quiet);
+        assert_eq!(
+            format!("{:?}", batch),
+            r#"Ok(Some([FileCreated("/a", None), FileModified("/a", None)]))"#
+        );
+    }
+
+    #[tokio::test]
+    async fn stopped_ends_the_batch_immediately() {
+        let input = stream::iter(vec![
+            FileSystemEvent::FileRemoved("/a".into()),
+            FileSystemEvent::Stopped(StopReason::DirectoryRemoved),
+        ])
+        .chain(stream::pending());
+        let mut batched = Batched::new(input, Duration::from_secs(60));
+        let batch = time::timeout(Duration::from_secs(1), batched.next()).await;
+        assert_eq!(
+            format!("{:?}", batch),
+            r#"Ok(Some([FileRemoved("/a"), Stopped(DirectoryRemoved)]))"#
+        );
+    }
+}
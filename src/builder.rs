@@ -0,0 +1,481 @@
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use glob::Pattern;
+
+use super::{Error, EventKindSet, FileSystemWatcher, UnreadableDirectoryPolicy};
+
+/// Builder to configure and create a `FileSystemWatcher`.
+///
+/// The default configuration matches the behavior of `FileSystemWatcher::new()`. Note that the
+/// options are currently only respected by the inotify backend.
+pub struct FileSystemWatcherBuilder {
+    roots: Vec<OsString>,
+    canonicalize: bool,
+    ignore_patterns: Vec<String>,
+    options: WatcherOptions,
+}
+
+impl FileSystemWatcherBuilder {
+    /// Creates a builder for a watcher which monitors the directory at `path`.
+    pub fn new(path: &OsStr) -> Self {
+        Self {
+            roots: vec![path.to_owned()],
+            canonicalize: false,
+            ignore_patterns: Vec::new(),
+            options: WatcherOptions::default(),
+        }
+    }
+
+    /// Adds another directory which shall be monitored by the same watcher.
+    ///
+    /// See `FileSystemWatcher::new_multi()` for details.
+    pub fn add_root(mut self, path: &OsStr) -> Self {
+        self.roots.push(path.to_owned());
+        self
+    }
+
+    /// Selects whether the root paths are converted into absolute paths without symbolic links
+    /// (default: `false`).
+    ///
+    /// Event paths start with the root path, so relative root paths result in relative event
+    /// paths which become invalid if the working directory of the process is changed. If set,
+    /// the roots are passed through `std::fs::canonicalize()` when the watcher is created, and
+    /// all event paths are absolute. Paths passed to `exclude_paths()` and `known_directories()`
+    /// have to be canonical as well.
+    pub fn canonicalize(mut self, canonicalize: bool) -> Self {
+        self.canonicalize = canonicalize;
+        self
+    }
+
+    /// Selects whether symbolic links to directories are followed when subdirectories are added
+    /// to the watcher (default: `false`).
+    ///
+    /// Directories which are reachable via multiple paths are only watched (and reported) once,
+    /// so links pointing to a parent directory do not cause infinite recursion.
+    pub fn follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.options.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    /// Selects whether subdirectories are monitored as well (default: `true`). If set to `false`,
+    /// only the root directory is watched, neither existing nor newly created subdirectories are
+    /// monitored. Events for the immediate children of the root directory are still generated.
+    pub fn recursive(mut self, recursive: bool) -> Self {
+        self.options.recursive = recursive;
+        self
+    }
+
+    /// Limits the depth of the monitored subdirectories (default: unlimited).
+    ///
+    /// A depth of 1 only monitors the immediate subdirectories of the root directory, a depth of
+    /// 0 is equivalent to `recursive(false)`. Events for the contents of the deepest monitored
+    /// directories are still generated, including `DirectoryCreated` for new subdirectories.
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.options.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Selects whether subdirectories on a different file system than their parent directory are
+    /// monitored (default: `true`).
+    ///
+    /// If set to `false`, mount points (including bind mounts) below the root directories are
+    /// skipped, so that large mounted volumes are not watched accidentally. The subdirectories
+    /// are still reported as `DirectoryCreated` if `emit_existing` is set, but changes within
+    /// them are not. Only supported on Linux.
+    pub fn cross_fs(mut self, cross_fs: bool) -> Self {
+        self.options.cross_fs = cross_fs;
+        self
+    }
+
+    /// Selects whether the watcher waits for removed root directories to be recreated (default:
+    /// `false`).
+    ///
+    /// If set, the removal of a root directory is reported as `DirectoryRemoved` instead of
+    /// `Stopped` or `RootStopped`, and the directory is watched again (generating a new
+    /// `DirectoryWatched` event) once it is recreated. Only if the parent directory is removed as
+    /// well, the root directory stops being monitored.
+    pub fn resilient_root(mut self, resilient_root: bool) -> Self {
+        self.options.resilient_root = resilient_root;
+        self
+    }
+
+    /// Selects whether the watches of a directory which is moved within the watched tree are kept
+    /// (default: `false`).
+    ///
+    /// By default, the watches for the directory and all its subdirectories are removed and
+    /// installed again at the new location, which is expensive for large trees and might miss
+    /// changes made right after the move. If set, the stored paths are rewritten instead, so no
+    /// `DirectoryWatched` events are generated for the moved directories.
+    pub fn rewrite_moved_paths(mut self, rewrite_moved_paths: bool) -> Self {
+        self.options.rewrite_moved_paths = rewrite_moved_paths;
+        self
+    }
+
+    /// Sets the size of the buffer used to read events from the operating system (default: 1024
+    /// bytes).
+    ///
+    /// The buffer has to be able to hold at least a single event including the file name, so the
+    /// backend might choose a larger buffer.
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.options.buffer_size = buffer_size;
+        self
+    }
+
+    /// Sets the maximum number of watches installed at once (default: 1).
+    ///
+    /// By default, every new directory is reported with a separate `DirectoryWatched` event. For
+    /// large trees, the initial scan is considerably faster if multiple watches are installed
+    /// whenever the stream is polled and reported together in a single `DirectoriesWatched`
+    /// event. Only supported on Linux.
+    pub fn watch_batch_size(mut self, watch_batch_size: usize) -> Self {
+        self.options.watch_batch_size = watch_batch_size;
+        self
+    }
+
+    /// Sets the maximum number of events and new directories buffered by the watcher (default: no
+    /// limit).
+    ///
+    /// Listing large directories with `emit_existing` and bursts of new directories can otherwise
+    /// make the internal queues grow without bound if the stream is polled slowly. Once the limit
+    /// is reached, further events and directories are dropped, the number of dropped entries is
+    /// counted in `Stats::dropped_events`, and an `Overflow` event is generated, so the caller has
+    /// to rescan the directories. Unmatched `MovedFrom` events are reported early instead of being
+    /// dropped. Only supported on Linux.
+    pub fn max_queued_events(mut self, max_queued_events: usize) -> Self {
+        self.options.max_queued_events = Some(max_queued_events);
+        self
+    }
+
+    /// Sets a number of watches above which a `WatchLimitWarning` event is generated (default:
+    /// no limit).
+    ///
+    /// The watcher keeps installing watches beyond the limit. The warning is meant as a leading
+    /// indicator before the kernel limit `fs.inotify.max_user_watches` is reached and watches
+    /// fail with `Error::WatchLimitExceeded`. Only supported on Linux.
+    pub fn soft_watch_limit(mut self, soft_watch_limit: usize) -> Self {
+        self.options.soft_watch_limit = Some(soft_watch_limit);
+        self
+    }
+
+    /// Selects whether metadata changes of files are reported as `FileModified` instead of
+    /// `FileAttributesChanged` (default: `false`).
+    pub fn fold_attribute_changes(mut self, fold_attribute_changes: bool) -> Self {
+        self.options.fold_attribute_changes = fold_attribute_changes;
+        self
+    }
+
+    /// Selects whether consecutive modifications of the same file are reported as a single
+    /// `FileModified` event (default: `false`).
+    ///
+    /// Only events which are read from the operating system at the same time are combined, see
+    /// `FileEventDelay` for a time-based alternative.
+    pub fn coalesce_modifications(mut self, coalesce_modifications: bool) -> Self {
+        self.options.coalesce_modifications = coalesce_modifications;
+        self
+    }
+
+    /// Selects whether existing files and directories are reported (default: `false`).
+    ///
+    /// If set, the watcher generates `FileCreated` and `DirectoryCreated` events for the existing
+    /// contents of every directory right after the corresponding `DirectoryWatched` event, so
+    /// that the caller does not need to scan the directories itself.
+    pub fn emit_existing(mut self, emit_existing: bool) -> Self {
+        self.options.emit_existing = emit_existing;
+        self
+    }
+
+    /// Selects whether the contents of newly created directories are reported (default:
+    /// `false`).
+    ///
+    /// The watch for a new directory is only installed after its `DirectoryCreated` event has
+    /// been read, so files which are created right after the directory are not reported.
+    /// If set, the new directory is listed right after its watch has been installed, and
+    /// `FileCreated` and `DirectoryCreated` events are generated for its contents like with
+    /// `emit_existing`, but without reporting the contents of the directories which existed
+    /// initially. Entries created after the watch was installed, but before the directory was
+    /// listed, are reported twice. Only supported on Linux.
+    pub fn scan_on_create(mut self, scan_on_create: bool) -> Self {
+        self.options.scan_on_create = scan_on_create;
+        self
+    }
+
+    /// Selects whether the stream ends after the initial scan (default: `false`).
+    ///
+    /// If set, all watches are removed right after `ScanComplete` has been returned and the
+    /// stream ends without a `Stopped` event. Combined with `emit_existing`, the watcher reports
+    /// the current contents of the tree once, see `FileSystemWatcher::snapshot()`. Only supported
+    /// on Linux.
+    pub fn stop_after_scan(mut self, stop_after_scan: bool) -> Self {
+        self.options.stop_after_scan = stop_after_scan;
+        self
+    }
+
+    /// Selects the types of events which are generated (default: all except `FileWriteClosed`,
+    /// `FileOpened`, `FileAccessed` and `FileClosed`).
+    ///
+    /// Compared to `FileSystemWatcher::events_of_kinds()`, the operating system is only asked to
+    /// report the selected kinds of modifications, which reduces the load for large trees with
+    /// frequently written files. Events which are always passed through by `EventKindSet` are
+    /// always generated, and subdirectories are monitored even if no directory events are
+    /// selected.
+    pub fn events(mut self, events: EventKindSet) -> Self {
+        self.options.events = events;
+        self
+    }
+
+    /// Selects whether events for files which have been removed while they are still open are
+    /// suppressed (default: `true`).
+    ///
+    /// By default, no further events are generated once a file has been removed, even if a
+    /// process still has it open and keeps writing to it. If set to `false`, such activity is
+    /// still reported, e.g., as `FileModified` for a removed log file which is still being
+    /// written. These events refer to the path at which the file was located before it was
+    /// removed, so the path may not exist anymore or may refer to a different file. Only
+    /// supported on Linux.
+    pub fn exclude_unlinked(mut self, exclude_unlinked: bool) -> Self {
+        self.options.exclude_unlinked = exclude_unlinked;
+        self
+    }
+
+    /// Selects whether `FileCreated` and `FileModified` events carry the metadata of the file
+    /// (default: `false`).
+    ///
+    /// The metadata contains the inode and device numbers, so that the same file can be
+    /// recognized under different names. The file is queried right after the event has been
+    /// read, so the metadata is missing if the file has already been removed, and it may describe
+    /// a newer version of the file, see `FileMetadata`. Only supported on Linux.
+    pub fn file_metadata(mut self, file_metadata: bool) -> Self {
+        self.options.file_metadata = file_metadata;
+        self
+    }
+
+    /// Selects whether `FileModified` events are suppressed if the size and the modification time
+    /// of the file did not change (default: `false`).
+    ///
+    /// Some programs rewrite files with identical contents and restore the modification time
+    /// afterwards. If this option is set, the watcher remembers the size and modification time of
+    /// each modified file and drops modifications which leave both unchanged. This costs one
+    /// `stat()` call per modification, and the state of every modified file is kept in memory
+    /// until the file is removed. The first modification of a file after the watcher was started
+    /// is always reported. Changes which keep the size and happen within the granularity of the
+    /// file system timestamps are suppressed as well. Only supported on Linux.
+    pub fn skip_unchanged_modifications(mut self, skip_unchanged_modifications: bool) -> Self {
+        self.options.skip_unchanged_modifications = skip_unchanged_modifications;
+        self
+    }
+
+    /// Selects whether events which refer to paths that are not valid UTF-8 are replaced by
+    /// `NonUtf8Path` events (default: `false`).
+    ///
+    /// By default, all paths are passed through as `OsString`, and code which converts them to
+    /// strings (e.g., for serialization) has to deal with invalid UTF-8 itself, usually by lossy
+    /// conversion which corrupts the file names. If set, such events are replaced by a
+    /// `NonUtf8Path` event which carries the unmodified path and the type of the original event.
+    /// See also `FileSystemEvent::non_utf8_path`. Only supported on Linux.
+    pub fn strict_paths(mut self, strict_paths: bool) -> Self {
+        self.options.strict_paths = strict_paths;
+        self
+    }
+
+    /// Selects whether the events reported by the operating system are returned as `Raw` events
+    /// for debugging (default: `false`).
+    ///
+    /// Each `Raw` event is directly followed by the translated event, if any. To only receive the
+    /// raw events, combine this option with `events(EventKindSet::RAW)`. Only supported on Linux.
+    pub fn raw_events(mut self, raw_events: bool) -> Self {
+        self.options.raw_events = raw_events;
+        self
+    }
+
+    /// Selects how directories are handled which cannot be monitored or listed because of missing
+    /// permissions (default: `UnreadableDirectoryPolicy::Report`).
+    ///
+    /// The subdirectories of such directories are not monitored either, so by default, a
+    /// `WatchFailed` event tells the caller that parts of the tree are not covered. Only
+    /// supported on Linux.
+    pub fn unreadable_directories(mut self, policy: UnreadableDirectoryPolicy) -> Self {
+        self.options.unreadable_directories = policy;
+        self
+    }
+
+    /// Adds a glob pattern for files and directories which shall not be monitored.
+    ///
+    /// Patterns without a slash are matched against the name of the file or directory (e.g.,
+    /// `target` or `*.swp`), other patterns are matched against the full path (e.g.,
+    /// `**/node_modules/**`). A path is also ignored if any of its parent directories is ignored.
+    /// No watches are installed for ignored directories, and no events are generated for ignored
+    /// files.
+    pub fn ignore(mut self, pattern: &str) -> Self {
+        self.ignore_patterns.push(pattern.to_owned());
+        self
+    }
+
+    /// Installs watches for exactly the specified directories instead of scanning the root
+    /// directories for subdirectories.
+    ///
+    /// This is meant for applications which persist the structure of a large tree, as listing
+    /// all directories again is slow. The list should contain the root directories as well as
+    /// all subdirectories, as no watches are installed for directories which are not listed,
+    /// and directories which do not exist anymore are skipped. Directories created later are
+    /// scanned as usual. `FileSystemWatcher::rescan()` can be used to catch up with changes made
+    /// while the application was not running. If `emit_existing` is set, no events are
+    /// generated for the contents of the listed directories. Only supported on Linux.
+    pub fn known_directories(mut self, directories: Vec<OsString>) -> Self {
+        self.options.known_directories = Some(directories);
+        self
+    }
+
+    /// Adds directories which shall not be monitored, including their subdirectories.
+    ///
+    /// Unlike `ignore()`, the paths are compared exactly: A subdirectory is excluded if it is one
+    /// of the paths or is located below one of them. The paths have to be specified in the same
+    /// form as the root directories, e.g., as absolute paths if the roots are absolute. Events for
+    /// the excluded directories themselves (e.g., `DirectoryCreated`) are still generated by the
+    /// watch of the parent directory.
+    pub fn exclude_paths(mut self, paths: Vec<OsString>) -> Self {
+        self.options.exclude.extend(paths);
+        self
+    }
+
+    /// Creates the watcher.
+    pub fn build(mut self) -> Result<FileSystemWatcher, Error> {
+        for pattern in self.ignore_patterns.iter() {
+            let pattern = match IgnorePattern::new(pattern) {
+                Some(pattern) => pattern,
+                None => return Err(Error::InvalidPattern(pattern.clone())),
+            };
+            self.options.ignore.push(pattern);
+        }
+        if self.canonicalize {
+            for root in self.roots.iter_mut() {
+                *root = match fs::canonicalize(Path::new(root)) {
+                    Ok(path) => path.into_os_string(),
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                        return Err(Error::NotFound(root.clone()))
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+            }
+        }
+        FileSystemWatcher::with_options(&self.roots, self.options)
+    }
+}
+
+/// Configuration passed from the builder to the platform-specific backend.
+#[derive(Clone, Debug)]
+pub(crate) struct WatcherOptions {
+    pub follow_symlinks: bool,
+    pub recursive: bool,
+    pub max_depth: Option<usize>,
+    pub cross_fs: bool,
+    pub buffer_size: usize,
+    pub watch_batch_size: usize,
+    pub soft_watch_limit: Option<usize>,
+    pub max_queued_events: Option<usize>,
+    pub fold_attribute_changes: bool,
+    pub coalesce_modifications: bool,
+    pub emit_existing: bool,
+    pub scan_on_create: bool,
+    pub stop_after_scan: bool,
+    pub resilient_root: bool,
+    pub rewrite_moved_paths: bool,
+    pub events: EventKindSet,
+    pub raw_events: bool,
+    pub file_metadata: bool,
+    pub skip_unchanged_modifications: bool,
+    pub strict_paths: bool,
+    pub exclude_unlinked: bool,
+    pub unreadable_directories: UnreadableDirectoryPolicy,
+    pub ignore: Vec<IgnorePattern>,
+    pub exclude: Vec<OsString>,
+    pub known_directories: Option<Vec<OsString>>,
+}
+
+impl WatcherOptions {
+    /// Returns whether the path matches any of the ignore patterns.
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        self.ignore.iter().any(|pattern| pattern.matches(path))
+    }
+
+    /// Returns whether the path is one of the excluded paths or located below one of them.
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        self.exclude
+            .iter()
+            .any(|excluded| path.starts_with(excluded))
+    }
+}
+
+impl Default for WatcherOptions {
+    fn default() -> Self {
+        Self {
+            follow_symlinks: false,
+            recursive: true,
+            max_depth: None,
+            cross_fs: true,
+            buffer_size: 1024,
+            watch_batch_size: 1,
+            soft_watch_limit: None,
+            max_queued_events: None,
+            fold_attribute_changes: false,
+            coalesce_modifications: false,
+            emit_existing: false,
+            scan_on_create: false,
+            stop_after_scan: false,
+            resilient_root: false,
+            rewrite_moved_paths: false,
+            events: EventKindSet::all()
+                - EventKindSet::FILE_WRITE_CLOSED
+                - EventKindSet::FILE_OPENED
+                - EventKindSet::FILE_ACCESSED
+                - EventKindSet::FILE_CLOSED,
+            raw_events: false,
+            file_metadata: false,
+            skip_unchanged_modifications: false,
+            strict_paths: false,
+            exclude_unlinked: true,
+            unreadable_directories: UnreadableDirectoryPolicy::Report,
+            ignore: Vec::new(),
+            exclude: Vec::new(),
+            known_directories: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct IgnorePattern {
+    pattern: Pattern,
+    /// Whether the pattern is only matched against the file name instead of the whole path.
+    name_only: bool,
+}
+
+impl IgnorePattern {
+    fn new(pattern: &str) -> Option<Self> {
+        // As the contents of ignored directories are ignored as well, "foo/**" is equivalent to
+        // "foo", but the latter also matches the directory itself so that it is not watched.
+        let pattern = if pattern.ends_with("/**") {
+            &pattern[..pattern.len() - 3]
+        } else {
+            pattern
+        };
+        Some(Self {
+            pattern: Pattern::new(pattern).ok()?,
+            name_only: !pattern.contains('/'),
+        })
+    }
+
+    /// Checks whether the pattern matches the path. Parent directories are not checked, as no
+    /// events are received for the contents of ignored directories anyways.
+    fn matches(&self, path: &Path) -> bool {
+        if self.name_only {
+            match path.file_name() {
+                Some(name) => self.pattern.matches(&name.to_string_lossy()),
+                None => false,
+            }
+        } else {
+            self.pattern.matches_path(path)
+        }
+    }
+}
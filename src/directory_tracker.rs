@@ -0,0 +1,120 @@
+//! Bookkeeping of watched directories for backends which always watch the whole subtree.
+
+use std::collections::BTreeSet;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::Path;
+
+use log::warn;
+
+use super::FileSystemEvent;
+
+/// Keeps track of the directories within the watched trees.
+///
+/// FSEvents and ReadDirectoryChangesW always report events for the whole subtree, so the backends
+/// only need to list the subdirectories to generate DirectoryWatched events in the same way as
+/// the inotify backend.
+pub struct DirectoryTracker {
+    new_directories: BTreeSet<OsString>,
+    watched_directories: BTreeSet<OsString>,
+}
+
+impl DirectoryTracker {
+    pub fn new(roots: &[OsString]) -> DirectoryTracker {
+        DirectoryTracker {
+            new_directories: roots.iter().cloned().collect(),
+            watched_directories: BTreeSet::new(),
+        }
+    }
+
+    /// Returns the directories which are currently being monitored.
+    pub fn watched_paths(&self) -> Vec<OsString> {
+        self.watched_directories.iter().cloned().collect()
+    }
+
+    /// Forgets all directories, called when the watcher is closed.
+    pub fn clear(&mut self) {
+        self.new_directories.clear();
+        self.watched_directories.clear();
+    }
+
+    /// Updates the directory lists according to an event reported by the backend.
+    pub fn handle_event(&mut self, event: &FileSystemEvent) {
+        match event {
+            FileSystemEvent::DirectoryCreated(path) => {
+                self.new_directories.insert(path.clone());
+            }
+            FileSystemEvent::DirectoryRemoved(path) => self.forget_directories(path),
+            FileSystemEvent::DirectoryMoved(from, to) => {
+                self.forget_directories(from);
+                self.new_directories.insert(to.clone());
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns a DirectoryWatched event for the next new directory, if there is any.
+    pub fn next_watched(&mut self) -> Option<FileSystemEvent> {
+        while let Some(new_directory) = self.new_directories.iter().next().cloned() {
+            self.new_directories.remove(&new_directory);
+            if Path::new(&new_directory).is_dir() {
+                self.watched_directories.insert(new_directory.clone());
+                self.watch_subdirectories(&new_directory);
+                return Some(FileSystemEvent::DirectoryWatched(new_directory));
+            }
+        }
+        None
+    }
+
+    /// Removes a directory and its subdirectories from the list of watched directories.
+    fn forget_directories(&mut self, path: &OsStr) {
+        let removed = self
+            .watched_directories
+            .iter()
+            .filter(|p| Path::new(p).starts_with(path))
+            .cloned()
+            .collect::<Vec<_>>();
+        for p in removed {
+            self.watched_directories.remove(&p);
+        }
+    }
+
+    fn watch_subdirectories(&mut self, path: &OsStr) {
+        match fs::read_dir(&path) {
+            Ok(entries) => {
+                for entry in entries {
+                    match entry {
+                        Ok(entry) => {
+                            match entry.file_type() {
+                                Ok(file_type) => {
+                                    if file_type.is_dir() {
+                                        let new_dir =
+                                            entry.path().as_path().as_os_str().to_os_string();
+                                        self.new_directories.insert(new_dir);
+                                    }
+                                }
+                                Err(e) => warn!(
+                                    "Cannot determine file type of {}: {:?}",
+                                    entry.path().as_path().to_str().unwrap_or("(non-UTF path)"),
+                                    e
+                                ),
+                            };
+                        }
+                        Err(e) => warn!(
+                            "Error during directory listing of {}: {:?}",
+                            path.to_string_lossy(),
+                            e
+                        ),
+                    };
+                }
+            }
+            Err(e) => {
+                warn!(
+                    "Cannot monitor directory {}: {:?}",
+                    path.to_string_lossy(),
+                    e
+                );
+            }
+        };
+    }
+}
@@ -0,0 +1,154 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bitflags::bitflags;
+use futures::stream::Stream;
+
+use super::{EventKind, FileSystemEvent};
+
+bitflags! {
+    /// Set of event types which are passed through by `EventFilter`.
+    pub struct EventKindSet: u32 {
+        const DIRECTORY_WATCHED = 1 << 0;
+        const DIRECTORY_CREATED = 1 << 1;
+        const DIRECTORY_MODIFIED = 1 << 2;
+        const DIRECTORY_REMOVED = 1 << 3;
+        const DIRECTORY_MOVED = 1 << 4;
+        const FILE_CREATED = 1 << 5;
+        const FILE_MODIFIED = 1 << 6;
+        const FILE_ATTRIBUTES_CHANGED = 1 << 7;
+        const FILE_REMOVED = 1 << 8;
+        const FILE_MOVED = 1 << 9;
+        const MOVED_FROM = 1 << 10;
+        const MOVED_TO = 1 << 11;
+        const FILE_WATCHED = 1 << 12;
+        const FILE_WRITE_CLOSED = 1 << 13;
+        const RAW = 1 << 14;
+        const FILE_OPENED = 1 << 15;
+        const FILE_ACCESSED = 1 << 16;
+        const FILE_CLOSED = 1 << 17;
+    }
+}
+
+impl EventKindSet {
+    /// Returns whether the event is part of the set.
+    ///
+    /// `Stopped`, `RootStopped`, `ScanComplete`, `Overflow`, `WatchFailed`, `WatchLimitWarning`,
+    /// `NonUtf8Path` and `Error` events are always part of the set, so that the caller does not
+    /// miss the termination of the stream, the end of the initial scan or any failures.
+    pub fn matches(&self, event: &FileSystemEvent) -> bool {
+        match EventKindSet::from_kind(event.kind()) {
+            Some(kind) => self.contains(kind),
+            None => true,
+        }
+    }
+
+    /// Returns the set containing only the specified event type, or `None` for the event types
+    /// which are always passed through.
+    pub fn from_kind(kind: EventKind) -> Option<EventKindSet> {
+        match kind {
+            EventKind::Stopped
+            | EventKind::RootStopped
+            | EventKind::ScanComplete
+            | EventKind::Overflow
+            | EventKind::WatchFailed
+            | EventKind::WatchLimitWarning
+            | EventKind::NonUtf8Path
+            | EventKind::Error => None,
+            EventKind::DirectoryWatched | EventKind::DirectoriesWatched => {
+                Some(EventKindSet::DIRECTORY_WATCHED)
+            }
+            EventKind::FileWatched => Some(EventKindSet::FILE_WATCHED),
+            EventKind::DirectoryCreated => Some(EventKindSet::DIRECTORY_CREATED),
+            EventKind::DirectoryModified => Some(EventKindSet::DIRECTORY_MODIFIED),
+            EventKind::DirectoryRemoved => Some(EventKindSet::DIRECTORY_REMOVED),
+            EventKind::DirectoryMoved => Some(EventKindSet::DIRECTORY_MOVED),
+            EventKind::FileCreated => Some(EventKindSet::FILE_CREATED),
+            EventKind::FileModified => Some(EventKindSet::FILE_MODIFIED),
+            EventKind::FileAttributesChanged => Some(EventKindSet::FILE_ATTRIBUTES_CHANGED),
+            EventKind::FileWriteClosed => Some(EventKindSet::FILE_WRITE_CLOSED),
+            EventKind::FileOpened => Some(EventKindSet::FILE_OPENED),
+            EventKind::FileAccessed => Some(EventKindSet::FILE_ACCESSED),
+            EventKind::FileClosed => Some(EventKindSet::FILE_CLOSED),
+            EventKind::FileRemoved => Some(EventKindSet::FILE_REMOVED),
+            EventKind::FileMoved => Some(EventKindSet::FILE_MOVED),
+            EventKind::MovedFrom => Some(EventKindSet::MOVED_FROM),
+            EventKind::MovedTo => Some(EventKindSet::MOVED_TO),
+            EventKind::Raw => Some(EventKindSet::RAW),
+        }
+    }
+}
+
+/// A stream which only passes through the events of the specified types.
+///
+/// See `FileSystemWatcher::events_of_kinds()`.
+pub struct EventFilter<T>
+where
+    T: Stream<Item = FileSystemEvent>,
+{
+    input: Pin<Box<T>>,
+    kinds: EventKindSet,
+}
+
+impl<T> EventFilter<T>
+where
+    T: Stream<Item = FileSystemEvent>,
+{
+    pub fn new(input: T, kinds: EventKindSet) -> Self {
+        Self {
+            input: Box::pin(input),
+            kinds,
+        }
+    }
+}
+
+impl<T> Stream for EventFilter<T>
+where
+    T: Stream<Item = FileSystemEvent>,
+{
+    type Item = FileSystemEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        // Safe, as we will not move self_.
+        let self_ = unsafe { self.get_unchecked_mut() };
+
+        loop {
+            match Pin::as_mut(&mut self_.input).poll_next(cx) {
+                Poll::Ready(Some(event)) => {
+                    if self_.kinds.matches(&event) {
+                        return Poll::Ready(Some(event));
+                    }
+                }
+                x => return x,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream::{self, StreamExt};
+
+    use super::*;
+    use crate::StopReason;
+
+    #[tokio::test]
+    async fn only_selected_events_pass() {
+        let input = stream::iter(vec![
+            FileSystemEvent::FileCreated("/a".into(), None),
+            FileSystemEvent::FileModified("/a".into(), None),
+            FileSystemEvent::DirectoryRemoved("/b".into()),
+            FileSystemEvent::FileRemoved("/a".into()),
+            FileSystemEvent::Stopped(StopReason::DirectoryRemoved),
+        ]);
+        let filter = EventFilter::new(
+            input,
+            EventKindSet::FILE_MODIFIED | EventKindSet::FILE_REMOVED,
+        );
+        let events = filter.collect::<Vec<_>>().await;
+        assert_eq!(
+            format!("{:?}", events),
+            r#"[FileModified("/a", None), FileRemoved("/a"), Stopped(DirectoryRemoved)]"#
+        );
+    }
+}
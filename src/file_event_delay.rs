@@ -1,11 +1,16 @@
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::future::Future;
 use std::mem;
+use std::path::Path;
 use std::pin::Pin;
+use std::slice;
 use std::task::{Context, Poll};
 use std::time::Duration;
 
 use futures::stream::Stream;
-use tokio::time::{self, Interval};
+use tokio::time::{self, Delay};
 
 use super::FileSystemEvent;
 
@@ -21,6 +26,11 @@ use super::FileSystemEvent;
 ///   type combines the two file system events for a moved file into the correct event. The delay
 ///   is required to make sure that the second event is available before the first has been passed
 ///   to the user of the library.
+///
+/// Events are returned between `min_delay` and twice `min_delay` after they were read from the
+/// input. All timers use the clock of the tokio runtime, so tests can make the timing
+/// deterministic by pausing the clock via `tokio::time::pause()` and advancing it via
+/// `tokio::time::advance()` (requires the `test-util` feature of tokio).
 pub struct FileEventDelay<T>
 where
     T: Stream<Item = FileSystemEvent>,
@@ -28,15 +38,44 @@ where
     input: Pin<Box<T>>,
 
     min_delay: Duration,
-    timer: Option<Pin<Box<Interval>>>,
+    /// Timer which expires `min_delay` after it was started. The timer is restarted whenever it
+    /// expires, as long as events are queued.
+    timer: Option<Pin<Box<Delay>>>,
+    /// Value of `min_delay` when the first event in the first array was queued. Used for the
+    /// timer once the events have been moved to the second array, so that `set_min_delay()` does
+    /// not affect events which are already queued.
+    queued_delay: Option<Duration>,
 
     /// File system events which are queued for processing.
     ///
     /// Whenever the timer expires, the events from the second array are processed and the content
-    /// of the first array is moved to the second. This way, each event is in the queue for a
-    /// duration of at least `min_delay`.
+    /// of the first array is moved to the second. As the timer is restarted afterwards, each event
+    /// is in the queue for a duration of at least `min_delay`.
     event_queue: (Vec<FileSystemEvent>, Vec<FileSystemEvent>),
     processed_events: VecDeque<FileSystemEvent>,
+    /// Set once the input stream has ended, the input must not be polled afterwards.
+    input_finished: bool,
+    /// Files reported by previous events, only tracked if atomic saves shall be detected.
+    known_files: Option<HashSet<OsString>>,
+    /// Maximum number of queued events and what happens if the limit is reached.
+    max_queued_events: Option<(usize, OverflowPolicy)>,
+    /// Number of events dropped because of `max_queued_events`.
+    dropped_events: u64,
+    /// Set once an `Overflow` event has been generated in the current timer period, so that a
+    /// burst of dropped events only generates a single `Overflow` event.
+    overflow_reported: bool,
+}
+
+/// Selects which events are dropped once the queue of a `FileEventDelay` is full, see
+/// `FileEventDelay::max_queued_events()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// The oldest queued event is dropped to make room for the new event.
+    DropOldest,
+    /// The new event is dropped.
+    DropNewest,
+    /// All queued events are dropped, as the caller has to rescan the directories anyways.
+    EmitOverflow,
 }
 
 impl<T> FileEventDelay<T>
@@ -48,20 +87,155 @@ where
             input: Box::pin(input),
             min_delay,
             timer: None,
+            queued_delay: None,
             event_queue: (Vec::new(), Vec::new()),
             processed_events: VecDeque::new(),
+            input_finished: false,
+            known_files: None,
+            max_queued_events: None,
+            dropped_events: 0,
+            overflow_reported: false,
         }
     }
 
-    fn process_events(&mut self) {
-        // TODO: Actually process and combine the events.
-        for event in self.event_queue.1.drain(..) {
-            self.processed_events.push_back(event);
+    /// Selects whether files which are replaced by moving another file onto them are reported as
+    /// modified (default: `false`).
+    ///
+    /// Many editors save files by writing a temporary file and moving it onto the original file.
+    /// If set, the stream keeps track of all files reported by previous events, and a move onto
+    /// one of these files is reported as `FileRemoved` for the source (which is dropped if the
+    /// source was created shortly before) and `FileModified` for the destination. Files which
+    /// existed before the watcher was created are known as well, as the directories are listed
+    /// when their `DirectoryWatched` events are read from the input.
+    pub fn detect_atomic_saves(mut self, detect_atomic_saves: bool) -> Self {
+        self.known_files = if detect_atomic_saves {
+            Some(HashSet::new())
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Limits the number of events which are queued while they are delayed (default:
+    /// unlimited).
+    ///
+    /// The stream reads all available events from the input, so if events are generated faster
+    /// than the delay allows to process them, the queue grows without bounds. Once the queue
+    /// contains `max_queued_events` events, events are dropped according to `policy`, and an
+    /// `Overflow` event is returned right away so that the caller knows that it has to rescan the
+    /// directories. A limit of 0 is treated as 1, as every event has to be queued at least until
+    /// the timer expires.
+    pub fn max_queued_events(mut self, max_queued_events: usize, policy: OverflowPolicy) -> Self {
+        self.max_queued_events = Some((max_queued_events.max(1), policy));
+        self
+    }
+
+    /// Changes the minimum delay of the events at runtime, e.g., to combine more events during
+    /// bulk operations.
+    ///
+    /// Events which are already queued keep their deadline, which is computed from the delay in
+    /// effect when they were queued. Events queued afterwards are delayed by at least the new
+    /// delay. Events read from the input within the same timer period share a deadline, so an
+    /// event which joins a period started before the change still uses the old delay.
+    pub fn set_min_delay(&mut self, min_delay: Duration) {
+        self.min_delay = min_delay;
+    }
+
+    /// Returns the minimum delay of the events.
+    pub fn min_delay(&self) -> Duration {
+        self.min_delay
+    }
+
+    /// Processes all queued events right away instead of waiting for the delay to expire, e.g.,
+    /// before shutting down.
+    ///
+    /// The events are returned by the next calls to `poll_next()` without waiting. Events which
+    /// have not been read from the input stream yet are not affected, and moves for which only
+    /// one half has been received are returned as `MovedFrom` or `MovedTo`.
+    pub fn flush(&mut self) {
+        self.flush_events();
+    }
+
+    /// Returns the number of events which were dropped because the queue was full.
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped_events
+    }
+
+    /// Adds an event from the input to the queue, dropping events if the queue is full.
+    fn queue_event(&mut self, event: FileSystemEvent) {
+        if let Some(known_files) = self.known_files.as_mut() {
+            add_existing_files(known_files, &event);
+        }
+        let (max_queued_events, policy) = match self.max_queued_events {
+            Some(limit) => limit,
+            None => {
+                self.event_queue.0.push(event);
+                return;
+            }
+        };
+        let queued = self.event_queue.0.len() + self.event_queue.1.len();
+        if queued < max_queued_events {
+            self.event_queue.0.push(event);
+            return;
+        }
+
+        if !self.overflow_reported {
+            self.processed_events.push_back(FileSystemEvent::Overflow);
+            self.overflow_reported = true;
+        }
+        match policy {
+            OverflowPolicy::DropOldest => {
+                // The second array contains the older events.
+                if self.event_queue.1.is_empty() {
+                    self.event_queue.0.remove(0);
+                } else {
+                    self.event_queue.1.remove(0);
+                }
+                self.dropped_events += 1;
+                self.event_queue.0.push(event);
+            }
+            OverflowPolicy::DropNewest => self.dropped_events += 1,
+            OverflowPolicy::EmitOverflow => {
+                self.dropped_events += queued as u64;
+                self.event_queue.0.clear();
+                self.event_queue.1.clear();
+                self.event_queue.0.push(event);
+            }
         }
+    }
+
+    fn process_events(&mut self) {
+        self.overflow_reported = false;
+        let events = mem::replace(&mut self.event_queue.1, Vec::new());
+        // The second half of a move might only be in the first array if it arrived up to one
+        // timer period later, so we look there as well. The matching events are removed from the
+        // first array, all other events stay there for the next period.
+        let events = combine_moves(events, &mut self.event_queue.0);
+        self.emit_processed(events);
+
+        // Move the remaining events to the second array. The second array is empty at this point,
+        // but we append instead of swapping so that no events can be lost if that ever changes.
+        self.event_queue.1.append(&mut self.event_queue.0);
+    }
+
+    /// Processes all queued events without waiting for the timer, as no further events will
+    /// arrive once the input has ended.
+    fn flush_events(&mut self) {
+        self.overflow_reported = false;
+        let mut events = mem::replace(&mut self.event_queue.1, Vec::new());
+        events.append(&mut self.event_queue.0);
+        let events = combine_moves(events, &mut Vec::new());
+        self.emit_processed(events);
+        self.timer = None;
+        self.queued_delay = None;
+    }
 
-        // Swap the arrays. This code assumes that the second array is now empty.
-        // TODO: Check the assumption.
-        mem::swap(&mut self.event_queue.0, &mut self.event_queue.1);
+    fn emit_processed(&mut self, events: Vec<FileSystemEvent>) {
+        let events = match self.known_files.as_mut() {
+            Some(known_files) => replace_atomic_saves(events, known_files),
+            None => events,
+        };
+        self.processed_events.extend(coalesce_events(events));
     }
 }
 
@@ -75,47 +249,295 @@ where
         // Safe, as we will not move self_.
         let self_ = unsafe { self.get_unchecked_mut() };
 
-        if self_.processed_events.is_empty() {
+        loop {
+            if let Some(next_event) = self_.processed_events.pop_front() {
+                return Poll::Ready(Some(next_event));
+            }
+
+            if self_.input_finished {
+                return Poll::Ready(None);
+            }
+
             // Fill the first array with incoming inotify events.
             while let Poll::Ready(inotify_event) = Pin::as_mut(&mut self_.input).poll_next(cx) {
-                if inotify_event.is_some() {
-                    self_.event_queue.0.push(inotify_event.unwrap());
-                } else {
-                    // TODO: Stream exhausted. Can this ever happen?
-                    return Poll::Ready(None);
+                match inotify_event {
+                    Some(event) => self_.queue_event(event),
+                    None => {
+                        // The watcher stopped, so we return the remaining events before ending
+                        // the stream as well.
+                        self_.input_finished = true;
+                        self_.flush_events();
+                        break;
+                    }
                 }
             }
+            if self_.input_finished {
+                continue;
+            }
 
             // If the first array is not empty, start a timer for processing if no timer is already
-            // running.
-            if !self_.event_queue.0.is_empty() && self_.timer.is_none() {
-                self_.timer = Some(Box::pin(time::interval(self_.min_delay)));
+            // running. The delay is remembered for the events in the first array.
+            if !self_.event_queue.0.is_empty() {
+                let queued_delay = *self_.queued_delay.get_or_insert(self_.min_delay);
+                if self_.timer.is_none() {
+                    self_.timer = Some(Box::pin(time::delay_for(queued_delay)));
+                }
             }
 
             // If the timer elapsed, process the events from the second array and move the content
-            // of the first array to the second.
-            if let Poll::Ready(Some(_)) =
-                Pin::as_mut(&mut self_.timer.as_mut().unwrap()).poll_next(cx)
-            {
-                self_.process_events();
-
-                // If no unprocessed events are available, stop the timer to reduce CPU
-                // consumption.
-                if self_.event_queue.1.is_empty() {
-                    self_.timer = None;
+            // of the first array to the second. If the timer is running, polling it registers the
+            // waker, so we must not return Poll::Pending without polling the current timer.
+            let timer = match self_.timer.as_mut() {
+                Some(timer) => timer,
+                None => return Poll::Pending,
+            };
+            if Pin::as_mut(timer).poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            let queued_delay = self_.queued_delay.take().unwrap_or(self_.min_delay);
+            self_.process_events();
+
+            // If no unprocessed events are available, stop the timer to reduce CPU consumption.
+            // Otherwise, the timer is restarted instead of using an interval, as an interval
+            // catches up with missed ticks and would then process the events too early. The new
+            // timer is polled in the next iteration. The events now in the second array keep the
+            // delay with which they were queued.
+            if self_.event_queue.1.is_empty() {
+                self_.timer = None;
+            } else {
+                self_.timer = Some(Box::pin(time::delay_for(queued_delay)));
+            }
+        }
+    }
+}
+
+/// Combines events which refer to the same path.
+///
+/// Multiple `FileModified` events for the same file are reduced to the first one, and a file
+/// which is created and removed again is not reported at all. The order of the remaining events
+/// is preserved.
+fn coalesce_events(events: Vec<FileSystemEvent>) -> Vec<FileSystemEvent> {
+    let mut result: Vec<Option<FileSystemEvent>> = Vec::with_capacity(events.len());
+    // Indices of the FileCreated and FileModified events for each path in `result`. The entries
+    // are removed whenever any other event refers to the path, as we must not move modifications
+    // across other events for the same file.
+    let mut created: HashMap<OsString, usize> = HashMap::new();
+    let mut modified: HashMap<OsString, usize> = HashMap::new();
+
+    for event in events {
+        match event {
+            FileSystemEvent::FileCreated(path, metadata) => {
+                modified.remove(&path);
+                created.insert(path.clone(), result.len());
+                result.push(Some(FileSystemEvent::FileCreated(path, metadata)));
+            }
+            FileSystemEvent::FileModified(path, metadata) => {
+                if !modified.contains_key(&path) {
+                    modified.insert(path.clone(), result.len());
+                    result.push(Some(FileSystemEvent::FileModified(path, metadata)));
+                }
+            }
+            FileSystemEvent::FileRemoved(path) => {
+                modified.remove(&path);
+                if let Some(created_index) = created.remove(&path) {
+                    // The file was short-lived, so we drop all events for it. Only modifications
+                    // can refer to the file after the creation, all other events would have
+                    // removed the entry from `created`.
+                    for entry in result[created_index..].iter_mut() {
+                        let short_lived = match entry {
+                            Some(FileSystemEvent::FileCreated(p, _))
+                            | Some(FileSystemEvent::FileModified(p, _))
+                            | Some(FileSystemEvent::FileAttributesChanged(p))
+                            | Some(FileSystemEvent::FileWriteClosed(p))
+                            | Some(FileSystemEvent::FileOpened(p))
+                            | Some(FileSystemEvent::FileAccessed(p))
+                            | Some(FileSystemEvent::FileClosed(p)) => *p == path,
+                            _ => false,
+                        };
+                        if short_lived {
+                            *entry = None;
+                        }
+                    }
+                } else {
+                    result.push(Some(FileSystemEvent::FileRemoved(path)));
                 }
-                // Return the first event if possible.
-                if let Some(next_event) = self_.processed_events.pop_front() {
-                    return Poll::Ready(Some(next_event));
+            }
+            FileSystemEvent::FileMoved(from, to) => {
+                for path in &[&from, &to] {
+                    created.remove(*path);
+                    modified.remove(*path);
+                }
+                result.push(Some(FileSystemEvent::FileMoved(from, to)));
+            }
+            event => {
+                match &event {
+                    FileSystemEvent::MovedFrom { path, .. }
+                    | FileSystemEvent::MovedTo { path, .. } => {
+                        created.remove(path);
+                        modified.remove(path);
+                    }
+                    _ => {}
                 }
+                result.push(Some(event));
             }
+        }
+    }
 
-            Poll::Pending
-        } else {
-            let next_event = self_.processed_events.pop_front().unwrap();
-            Poll::Ready(Some(next_event))
+    result.into_iter().filter_map(|event| event).collect()
+}
+
+/// Combines `MovedFrom` events with the `MovedTo` events with the same cookie, either from
+/// `events` or from `later_events`. Events without a matching counterpart are left unchanged.
+fn combine_moves(
+    mut events: Vec<FileSystemEvent>,
+    later_events: &mut Vec<FileSystemEvent>,
+) -> Vec<FileSystemEvent> {
+    let mut result = Vec::with_capacity(events.len());
+    // Reversed, so that we can efficiently take events from the front.
+    events.reverse();
+    while let Some(event) = events.pop() {
+        match event {
+            FileSystemEvent::MovedFrom {
+                path,
+                cookie,
+                is_dir,
+            } => {
+                let to = take_moved_to(&mut events, cookie)
+                    .or_else(|| take_moved_to(later_events, cookie));
+                match to {
+                    Some(to) if is_dir => result.push(FileSystemEvent::DirectoryMoved(path, to)),
+                    Some(to) => result.push(FileSystemEvent::FileMoved(path, to)),
+                    None => result.push(FileSystemEvent::MovedFrom {
+                        path,
+                        cookie,
+                        is_dir,
+                    }),
+                }
+            }
+            event => result.push(event),
         }
     }
+    result
+}
+
+/// Replaces moves onto known files with a removal of the source and a modification of the
+/// destination, and updates the set of known files.
+fn replace_atomic_saves(
+    events: Vec<FileSystemEvent>,
+    known_files: &mut HashSet<OsString>,
+) -> Vec<FileSystemEvent> {
+    let mut result = Vec::with_capacity(events.len());
+    for event in events {
+        match event {
+            FileSystemEvent::FileMoved(from, to) if known_files.contains(&to) => {
+                known_files.remove(&from);
+                result.push(FileSystemEvent::FileRemoved(from));
+                result.push(FileSystemEvent::FileModified(to, None));
+            }
+            FileSystemEvent::MovedTo {
+                path,
+                is_dir: false,
+                ..
+            } if known_files.contains(&path) => {
+                result.push(FileSystemEvent::FileModified(path, None));
+            }
+            event => {
+                update_known_files(known_files, &event);
+                result.push(event);
+            }
+        }
+    }
+    result
+}
+
+fn update_known_files(known_files: &mut HashSet<OsString>, event: &FileSystemEvent) {
+    match event {
+        FileSystemEvent::FileCreated(path, _)
+        | FileSystemEvent::FileModified(path, _)
+        | FileSystemEvent::FileAttributesChanged(path)
+        | FileSystemEvent::FileWriteClosed(path)
+        | FileSystemEvent::FileOpened(path)
+        | FileSystemEvent::FileAccessed(path)
+        | FileSystemEvent::FileClosed(path)
+        | FileSystemEvent::MovedTo {
+            path,
+            is_dir: false,
+            ..
+        } => {
+            known_files.insert(path.clone());
+        }
+        FileSystemEvent::FileRemoved(path)
+        | FileSystemEvent::MovedFrom {
+            path,
+            is_dir: false,
+            ..
+        } => {
+            known_files.remove(path);
+        }
+        FileSystemEvent::FileMoved(from, to) => {
+            known_files.remove(from);
+            known_files.insert(to.clone());
+        }
+        FileSystemEvent::DirectoryRemoved(path)
+        | FileSystemEvent::MovedFrom {
+            path, is_dir: true, ..
+        } => {
+            known_files.retain(|file| !Path::new(file).starts_with(path));
+        }
+        FileSystemEvent::DirectoryMoved(from, to) => {
+            let moved = known_files
+                .iter()
+                .filter(|file| Path::new(file).starts_with(from))
+                .cloned()
+                .collect::<Vec<_>>();
+            for file in moved {
+                known_files.remove(&file);
+                let relative = Path::new(&file).strip_prefix(from).unwrap();
+                known_files.insert(Path::new(to).join(relative).into_os_string());
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Registers the files in newly watched directories as known files.
+///
+/// The directories are listed when the event is read from the input instead of when it is
+/// processed, as files moved into the directory in the meantime must not be known yet.
+fn add_existing_files(known_files: &mut HashSet<OsString>, event: &FileSystemEvent) {
+    let directories = match event {
+        FileSystemEvent::DirectoryWatched(path) => slice::from_ref(path),
+        FileSystemEvent::DirectoriesWatched(paths) => paths.as_slice(),
+        _ => return,
+    };
+    for directory in directories {
+        list_files(known_files, directory);
+    }
+}
+
+fn list_files(known_files: &mut HashSet<OsString>, directory: &OsStr) {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        // The watcher reports errors for directories which cannot be listed.
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        if entry.file_type().map(|t| !t.is_dir()).unwrap_or(false) {
+            known_files.insert(entry.path().into_os_string());
+        }
+    }
+}
+
+/// Removes the `MovedTo` event with the specified cookie from the list and returns its path.
+fn take_moved_to(events: &mut Vec<FileSystemEvent>, cookie: u32) -> Option<OsString> {
+    let index = events.iter().position(|event| match event {
+        FileSystemEvent::MovedTo { cookie: c, .. } => *c == cookie,
+        _ => false,
+    })?;
+    match events.remove(index) {
+        FileSystemEvent::MovedTo { path, .. } => Some(path),
+        _ => unreachable!(),
+    }
 }
 
 /*pub struct NextFileEvent<'a, T> where T: Stream<Item = io::Result<inotify::EventOwned>> {
@@ -134,3 +556,455 @@ impl<'a, T> Future for NextFileEvent<'a, T> where T: Stream<Item = io::Result<in
 
     }
 }*/
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+    use std::time::Duration;
+
+    use futures::stream::{self, StreamExt};
+    use futures::FutureExt;
+    use tokio::time;
+
+    use super::*;
+    use crate::StopReason;
+
+    /// Passes the events through a `FileEventDelay` and collects the output until no more events
+    /// are produced.
+    async fn delay_events(input: Vec<FileSystemEvent>) -> Vec<FileSystemEvent> {
+        let input = stream::iter(input).chain(stream::pending());
+        let mut delay = FileEventDelay::new(input, Duration::from_millis(10));
+        let mut events = Vec::new();
+        while let Ok(Some(event)) = time::timeout(Duration::from_millis(100), delay.next()).await {
+            events.push(event);
+        }
+        events
+    }
+
+    #[tokio::test]
+    async fn events_are_delayed_by_min_delay() {
+        let min_delay = Duration::from_millis(50);
+        let start = time::Instant::now();
+        let input = stream::iter(vec![
+            FileSystemEvent::FileCreated(path("/a"), None),
+            FileSystemEvent::FileCreated(path("/b"), None),
+        ])
+        .chain(stream::pending());
+        let mut delay = FileEventDelay::new(input, min_delay);
+        for _ in 0..2 {
+            assert!(delay.next().await.is_some());
+            assert!(start.elapsed() >= min_delay);
+        }
+    }
+
+    #[tokio::test]
+    async fn single_event_is_emitted_without_further_input() {
+        let input = stream::iter(vec![FileSystemEvent::FileModified(path("/a"), None)])
+            .chain(stream::pending());
+        let mut delay = FileEventDelay::new(input, Duration::from_millis(10));
+        // The timer has to wake up the task, as the input never becomes ready again.
+        let event = time::timeout(Duration::from_secs(1), delay.next()).await;
+        assert_eq!(
+            format!("{:?}", event),
+            r#"Ok(Some(FileModified("/a", None)))"#
+        );
+    }
+
+    #[tokio::test]
+    async fn min_delay_can_be_changed() {
+        let input = stream::iter(vec![FileSystemEvent::FileCreated(path("/a"), None)])
+            .chain(stream::pending());
+        let mut delay = FileEventDelay::new(input, Duration::from_secs(60));
+        delay.set_min_delay(Duration::from_millis(10));
+        assert_eq!(delay.min_delay(), Duration::from_millis(10));
+        let event = time::timeout(Duration::from_secs(1), delay.next()).await;
+        assert_eq!(
+            format!("{:?}", event),
+            r#"Ok(Some(FileCreated("/a", None)))"#
+        );
+    }
+
+    #[tokio::test]
+    async fn events_are_emitted_after_two_timer_periods() {
+        time::pause();
+        let min_delay = Duration::from_secs(10);
+        let input = stream::iter(vec![FileSystemEvent::FileCreated(path("/a"), None)])
+            .chain(stream::pending());
+        let mut delay = FileEventDelay::new(input, min_delay);
+        // The first poll reads the event and starts the timer. When the timer expires for the
+        // first time, the event is only moved to the second array.
+        assert!(delay.next().now_or_never().is_none());
+        time::advance(min_delay).await;
+        assert!(delay.next().now_or_never().is_none());
+        time::advance(min_delay - Duration::from_millis(1)).await;
+        assert!(delay.next().now_or_never().is_none());
+        time::advance(Duration::from_millis(1)).await;
+        assert_eq!(
+            format!("{:?}", delay.next().now_or_never()),
+            r#"Some(Some(FileCreated("/a", None)))"#
+        );
+    }
+
+    #[tokio::test]
+    async fn events_of_one_period_are_emitted_together() {
+        time::pause();
+        let min_delay = Duration::from_secs(10);
+        let (mut sender, receiver) = futures::channel::mpsc::unbounded();
+        let mut delay = FileEventDelay::new(receiver, min_delay);
+        sender
+            .unbounded_send(FileSystemEvent::FileCreated(path("/a"), None))
+            .unwrap();
+        assert!(delay.next().now_or_never().is_none());
+        // Events received before the timer expires for the first time are processed together.
+        time::advance(min_delay / 2).await;
+        sender
+            .unbounded_send(FileSystemEvent::FileCreated(path("/b"), None))
+            .unwrap();
+        assert!(delay.next().now_or_never().is_none());
+        time::advance(min_delay / 2).await;
+        assert!(delay.next().now_or_never().is_none());
+        time::advance(min_delay).await;
+        let events = (0..2)
+            .map(|_| delay.next().now_or_never())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            format!("{:?}", events),
+            r#"[Some(Some(FileCreated("/a", None))), Some(Some(FileCreated("/b", None)))]"#
+        );
+    }
+
+    #[tokio::test]
+    async fn queued_events_keep_their_delay() {
+        time::pause();
+        let (mut sender, receiver) = futures::channel::mpsc::unbounded();
+        let mut delay = FileEventDelay::new(receiver, Duration::from_secs(10));
+        sender
+            .unbounded_send(FileSystemEvent::FileCreated(path("/a"), None))
+            .unwrap();
+        assert!(delay.next().now_or_never().is_none());
+        delay.set_min_delay(Duration::from_secs(60));
+        time::advance(Duration::from_secs(10)).await;
+        assert!(delay.next().now_or_never().is_none());
+        // Queued after the change, so the event is delayed by the new delay.
+        sender
+            .unbounded_send(FileSystemEvent::FileCreated(path("/b"), None))
+            .unwrap();
+        assert!(delay.next().now_or_never().is_none());
+        time::advance(Duration::from_secs(10)).await;
+        assert_eq!(
+            format!("{:?}", delay.next().now_or_never()),
+            r#"Some(Some(FileCreated("/a", None)))"#
+        );
+        assert!(delay.next().now_or_never().is_none());
+        time::advance(Duration::from_secs(59)).await;
+        assert!(delay.next().now_or_never().is_none());
+        time::advance(Duration::from_secs(1)).await;
+        assert_eq!(
+            format!("{:?}", delay.next().now_or_never()),
+            r#"Some(Some(FileCreated("/b", None)))"#
+        );
+    }
+
+    #[tokio::test]
+    async fn flushed_events_are_emitted_immediately() {
+        let input = stream::iter(vec![
+            FileSystemEvent::FileCreated(path("/a"), None),
+            FileSystemEvent::FileModified(path("/a"), None),
+        ])
+        .chain(stream::pending());
+        let mut delay = FileEventDelay::new(input, Duration::from_secs(60));
+        // Read the input into the queue.
+        let event = time::timeout(Duration::from_millis(100), delay.next()).await;
+        assert!(event.is_err());
+
+        delay.flush();
+        let events = time::timeout(
+            Duration::from_millis(100),
+            delay.by_ref().take(2).collect::<Vec<_>>(),
+        )
+        .await;
+        assert_eq!(
+            format!("{:?}", events),
+            r#"Ok([FileCreated("/a", None), FileModified("/a", None)])"#
+        );
+    }
+
+    #[tokio::test]
+    async fn queued_events_are_emitted_when_the_input_ends() {
+        let input = stream::iter(vec![
+            FileSystemEvent::FileCreated(path("/a"), None),
+            FileSystemEvent::MovedFrom {
+                path: path("/b"),
+                cookie: 1,
+                is_dir: false,
+            },
+            FileSystemEvent::MovedTo {
+                path: path("/c"),
+                cookie: 1,
+                is_dir: false,
+            },
+            FileSystemEvent::Stopped(StopReason::DirectoryRemoved),
+        ]);
+        // The events are emitted right away instead of after the delay.
+        let delay = FileEventDelay::new(input, Duration::from_secs(60));
+        let events = time::timeout(Duration::from_secs(1), delay.collect::<Vec<_>>()).await;
+        assert_eq!(
+            format!("{:?}", events),
+            r#"Ok([FileCreated("/a", None), FileMoved("/b", "/c"), Stopped(DirectoryRemoved)])"#
+        );
+    }
+
+    fn path(name: &str) -> OsString {
+        OsString::from(name)
+    }
+
+    #[tokio::test]
+    async fn modifications_are_combined() {
+        let events = delay_events(vec![
+            FileSystemEvent::FileModified(path("/a"), None),
+            FileSystemEvent::FileModified(path("/b"), None),
+            FileSystemEvent::FileModified(path("/a"), None),
+            FileSystemEvent::FileModified(path("/a"), None),
+        ])
+        .await;
+        assert_eq!(
+            format!("{:?}", events),
+            r#"[FileModified("/a", None), FileModified("/b", None)]"#
+        );
+    }
+
+    #[tokio::test]
+    async fn short_lived_files_are_dropped() {
+        let events = delay_events(vec![
+            FileSystemEvent::FileCreated(path("/tmp"), None),
+            FileSystemEvent::FileCreated(path("/a"), None),
+            FileSystemEvent::FileModified(path("/tmp"), None),
+            FileSystemEvent::FileRemoved(path("/tmp")),
+            FileSystemEvent::FileRemoved(path("/b")),
+        ])
+        .await;
+        assert_eq!(
+            format!("{:?}", events),
+            r#"[FileCreated("/a", None), FileRemoved("/b")]"#
+        );
+    }
+
+    #[tokio::test]
+    async fn moves_are_combined() {
+        let events = delay_events(vec![
+            FileSystemEvent::MovedFrom {
+                path: path("/a"),
+                cookie: 1,
+                is_dir: false,
+            },
+            FileSystemEvent::FileModified(path("/c"), None),
+            FileSystemEvent::MovedTo {
+                path: path("/b"),
+                cookie: 1,
+                is_dir: false,
+            },
+        ])
+        .await;
+        assert_eq!(
+            format!("{:?}", events),
+            r#"[FileMoved("/a", "/b"), FileModified("/c", None)]"#
+        );
+    }
+
+    #[tokio::test]
+    async fn queued_events_are_limited() {
+        let policies = [
+            (
+                OverflowPolicy::DropOldest,
+                r#"[Overflow, FileCreated("/d", None), FileCreated("/e", None)]"#,
+                3,
+            ),
+            (
+                OverflowPolicy::DropNewest,
+                r#"[Overflow, FileCreated("/a", None), FileCreated("/b", None)]"#,
+                3,
+            ),
+            (
+                OverflowPolicy::EmitOverflow,
+                r#"[Overflow, FileCreated("/e", None)]"#,
+                4,
+            ),
+        ];
+        for (policy, expected, dropped) in policies.iter() {
+            let input = stream::iter(
+                ["/a", "/b", "/c", "/d", "/e"]
+                    .iter()
+                    .map(|name| FileSystemEvent::FileCreated(path(name), None))
+                    .collect::<Vec<_>>(),
+            );
+            let mut delay =
+                FileEventDelay::new(input, Duration::from_millis(10)).max_queued_events(2, *policy);
+            let mut events = Vec::new();
+            while let Some(event) = delay.next().await {
+                events.push(event);
+            }
+            assert_eq!(format!("{:?}", events), *expected);
+            assert_eq!(delay.dropped_events(), *dropped);
+        }
+    }
+
+    #[tokio::test]
+    async fn queue_limit_of_zero_is_treated_as_one() {
+        let policies = [
+            (
+                OverflowPolicy::DropOldest,
+                r#"[Overflow, FileCreated("/e", None)]"#,
+            ),
+            (
+                OverflowPolicy::DropNewest,
+                r#"[Overflow, FileCreated("/a", None)]"#,
+            ),
+            (
+                OverflowPolicy::EmitOverflow,
+                r#"[Overflow, FileCreated("/e", None)]"#,
+            ),
+        ];
+        for (policy, expected) in policies.iter() {
+            let input = stream::iter(
+                ["/a", "/b", "/c", "/d", "/e"]
+                    .iter()
+                    .map(|name| FileSystemEvent::FileCreated(path(name), None))
+                    .collect::<Vec<_>>(),
+            );
+            let mut delay =
+                FileEventDelay::new(input, Duration::from_millis(10)).max_queued_events(0, *policy);
+            let mut events = Vec::new();
+            while let Some(event) = delay.next().await {
+                events.push(event);
+            }
+            assert_eq!(format!("{:?}", events), *expected);
+            assert_eq!(delay.dropped_events(), 4);
+        }
+    }
+
+    #[tokio::test]
+    async fn atomic_saves_are_reported_as_modifications() {
+        let input = stream::iter(vec![
+            FileSystemEvent::FileCreated(path("/a"), None),
+            FileSystemEvent::FileCreated(path("/a.tmp"), None),
+            FileSystemEvent::FileModified(path("/a.tmp"), None),
+            FileSystemEvent::MovedFrom {
+                path: path("/a.tmp"),
+                cookie: 1,
+                is_dir: false,
+            },
+            FileSystemEvent::MovedTo {
+                path: path("/a"),
+                cookie: 1,
+                is_dir: false,
+            },
+            FileSystemEvent::MovedFrom {
+                path: path("/a"),
+                cookie: 2,
+                is_dir: false,
+            },
+            FileSystemEvent::MovedTo {
+                path: path("/b"),
+                cookie: 2,
+                is_dir: false,
+            },
+        ]);
+        let delay = FileEventDelay::new(input, Duration::from_millis(10)).detect_atomic_saves(true);
+        let events = delay.collect::<Vec<_>>().await;
+        // The second move does not replace a known file.
+        assert_eq!(
+            format!("{:?}", events),
+            r#"[FileCreated("/a", None), FileModified("/a", None), FileMoved("/a", "/b")]"#
+        );
+    }
+
+    #[tokio::test]
+    async fn atomic_saves_of_existing_files_are_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("a").into_os_string();
+        let temp = dir.path().join("a.tmp").into_os_string();
+        fs::write(&target, "a").unwrap();
+        // The file existed before the watcher started, so it is only known from the listing of
+        // the watched directory.
+        let input = stream::iter(vec![
+            FileSystemEvent::DirectoryWatched(dir.path().into()),
+            FileSystemEvent::FileCreated(temp.clone(), None),
+            FileSystemEvent::MovedFrom {
+                path: temp.clone(),
+                cookie: 1,
+                is_dir: false,
+            },
+            FileSystemEvent::MovedTo {
+                path: target.clone(),
+                cookie: 1,
+                is_dir: false,
+            },
+        ]);
+        let delay = FileEventDelay::new(input, Duration::from_millis(10)).detect_atomic_saves(true);
+        let events = delay.collect::<Vec<_>>().await;
+        assert_eq!(
+            format!("{:?}", events),
+            format!(
+                "{:?}",
+                vec![
+                    FileSystemEvent::DirectoryWatched(dir.path().into()),
+                    FileSystemEvent::FileModified(target, None),
+                ]
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn moves_are_combined_across_timer_periods() {
+        // The MovedTo event arrives after the timer already moved the MovedFrom event into the
+        // second array.
+        let from = FileSystemEvent::MovedFrom {
+            path: path("/a"),
+            cookie: 1,
+            is_dir: false,
+        };
+        let input = stream::iter(vec![from])
+            .chain(stream::once(async {
+                time::delay_for(Duration::from_millis(15)).await;
+                FileSystemEvent::MovedTo {
+                    path: path("/b"),
+                    cookie: 1,
+                    is_dir: false,
+                }
+            }))
+            .chain(stream::pending());
+        let mut delay = FileEventDelay::new(input, Duration::from_millis(10));
+        let mut events = Vec::new();
+        while let Ok(Some(event)) = time::timeout(Duration::from_millis(100), delay.next()).await {
+            events.push(event);
+        }
+        assert_eq!(format!("{:?}", events), r#"[FileMoved("/a", "/b")]"#);
+    }
+
+    #[tokio::test]
+    async fn unrelated_events_are_not_combined() {
+        let events = delay_events(vec![
+            FileSystemEvent::FileRemoved(path("/a")),
+            FileSystemEvent::FileCreated(path("/b"), None),
+            FileSystemEvent::MovedFrom {
+                path: path("/c"),
+                cookie: 1,
+                is_dir: false,
+            },
+            FileSystemEvent::MovedTo {
+                path: path("/d"),
+                cookie: 2,
+                is_dir: false,
+            },
+        ])
+        .await;
+        assert_eq!(
+            format!("{:?}", events),
+            concat!(
+                r#"[FileRemoved("/a"), FileCreated("/b", None), "#,
+                r#"MovedFrom { path: "/c", cookie: 1, is_dir: false }, "#,
+                r#"MovedTo { path: "/d", cookie: 2, is_dir: false }]"#
+            )
+        );
+    }
+}
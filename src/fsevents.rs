@@ -0,0 +1,316 @@
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::ffi::{CStr, OsStr, OsString};
+use std::io;
+use std::os::raw::{c_char, c_void};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::pin::Pin;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc as std_mpsc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread::{self, JoinHandle};
+
+use fsevent_sys as fse;
+use fsevent_sys::core_foundation as cf;
+use futures::Stream;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use super::{FileSystemEvent, StopReason};
+use crate::directory_tracker::DirectoryTracker;
+
+/// Latency (in seconds) passed to FSEvents. FSEvents coalesces events which happen within this
+/// interval.
+const LATENCY: f64 = 0.05;
+
+/// Maximum time (in seconds) the run loop runs before checking whether the watcher has been
+/// closed. Only relevant if CFRunLoopStop() is called before the run loop has been entered.
+const STOP_CHECK_INTERVAL: f64 = 0.1;
+
+pub struct FileSystemWatcherFSEvents {
+    directories: DirectoryTracker,
+    events: UnboundedReceiver<FileSystemEvent>,
+    /// Run loop of the thread which receives the FSEvents callbacks, stored as an integer so that
+    /// the watcher stays `Send`.
+    run_loop: usize,
+    /// Set when the watcher is closed, checked by the run loop thread.
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FileSystemWatcherFSEvents {
+    pub fn new(roots: &[OsString]) -> Result<FileSystemWatcherFSEvents, super::Error> {
+        // FSEvents only accepts paths which can be converted to a CFString.
+        let mut root_dirs = Vec::new();
+        for root in roots {
+            match root.to_str() {
+                Some(root_dir) => root_dirs.push(root_dir.to_owned()),
+                None => {
+                    return Err(
+                        io::Error::new(io::ErrorKind::InvalidInput, "non-UTF-8 root path").into(),
+                    )
+                }
+            }
+        }
+
+        let (sender, events) = mpsc::unbounded_channel();
+        let (run_loop_sender, run_loop_receiver) = std_mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::spawn(move || {
+            run_event_stream(root_dirs, sender, run_loop_sender, thread_stop)
+        });
+        let run_loop = match run_loop_receiver.recv() {
+            Ok(Ok(run_loop)) => run_loop,
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => {
+                return Err(io::Error::new(io::ErrorKind::Other, "FSEvents thread died").into())
+            }
+        };
+
+        Ok(FileSystemWatcherFSEvents {
+            directories: DirectoryTracker::new(roots),
+            events,
+            run_loop,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Stops the FSEvents stream. Afterwards, the stream does not return any further events.
+    pub fn close(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            // The thread might not have entered the run loop yet, in which case CFRunLoopStop()
+            // has no effect. The flag makes sure that the thread still exits.
+            self.stop.store(true, Ordering::SeqCst);
+            unsafe {
+                cf::CFRunLoopStop(self.run_loop as cf::CFRunLoopRef);
+            }
+            thread.join().ok();
+        }
+        self.directories.clear();
+    }
+
+    /// Returns the directories which are currently being monitored.
+    pub fn watched_paths(&self) -> Vec<OsString> {
+        self.directories.watched_paths()
+    }
+}
+
+impl Stream for FileSystemWatcherFSEvents {
+    type Item = FileSystemEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        // Safe, as we will not move self_.
+        let self_ = unsafe { self.get_unchecked_mut() };
+
+        if self_.thread.is_none() {
+            // The watcher has been closed.
+            return Poll::Ready(None);
+        }
+
+        // Report changes before we report any new directories, so that the order of the events
+        // matches the inotify backend.
+        match self_.events.poll_recv(cx) {
+            Poll::Ready(Some(event)) => {
+                self_.directories.handle_event(&event);
+                return Poll::Ready(Some(event));
+            }
+            Poll::Ready(None) => return Poll::Ready(None),
+            Poll::Pending => {}
+        }
+
+        match self_.directories.next_watched() {
+            Some(event) => Poll::Ready(Some(event)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for FileSystemWatcherFSEvents {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+struct CallbackContext {
+    /// Root directories which have not been removed yet. Only accessed from the run loop thread.
+    roots: RefCell<BTreeSet<OsString>>,
+    sender: UnboundedSender<FileSystemEvent>,
+}
+
+/// Creates an FSEvents stream for the root directories and runs a run loop which receives the
+/// callbacks until the watcher is closed.
+fn run_event_stream(
+    root_dirs: Vec<String>,
+    sender: UnboundedSender<FileSystemEvent>,
+    run_loop_sender: std_mpsc::Sender<io::Result<usize>>,
+    stop: Arc<AtomicBool>,
+) {
+    let context = Box::into_raw(Box::new(CallbackContext {
+        roots: RefCell::new(root_dirs.iter().map(OsString::from).collect()),
+        sender,
+    }));
+
+    unsafe {
+        let paths =
+            cf::CFArrayCreateMutable(cf::kCFAllocatorDefault, 0, &cf::kCFTypeArrayCallBacks);
+        for root_dir in root_dirs.iter() {
+            let mut err: cf::CFErrorRef = ptr::null_mut();
+            let cf_path = cf::str_path_to_cfstring_ref(root_dir, &mut err);
+            if cf_path.is_null() {
+                cf::CFRelease(err as cf::CFRef);
+                cf::CFRelease(paths);
+                drop(Box::from_raw(context));
+                run_loop_sender
+                    .send(Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "cannot convert path for FSEvents",
+                    )))
+                    .ok();
+                return;
+            }
+            cf::CFArrayAppendValue(paths, cf_path);
+            cf::CFRelease(cf_path);
+        }
+
+        let stream_context = fse::FSEventStreamContext {
+            version: 0,
+            info: context as *mut c_void,
+            retain: None,
+            release: None,
+            copy_description: None,
+        };
+        let stream = fse::FSEventStreamCreate(
+            cf::kCFAllocatorDefault,
+            callback,
+            &stream_context,
+            paths,
+            fse::kFSEventStreamEventIdSinceNow,
+            LATENCY,
+            fse::kFSEventStreamCreateFlagFileEvents
+                | fse::kFSEventStreamCreateFlagNoDefer
+                | fse::kFSEventStreamCreateFlagWatchRoot,
+        );
+        cf::CFRelease(paths);
+
+        let run_loop = cf::CFRunLoopGetCurrent();
+        fse::FSEventStreamScheduleWithRunLoop(stream, run_loop, cf::kCFRunLoopDefaultMode);
+        fse::FSEventStreamStart(stream);
+        run_loop_sender.send(Ok(run_loop as usize)).ok();
+
+        // CFRunLoopStop() interrupts the run loop when the watcher is closed. The loop only runs
+        // for a limited time so that the stop flag is still noticed if CFRunLoopStop() was called
+        // before the run loop was entered.
+        while !stop.load(Ordering::SeqCst) {
+            cf::CFRunLoopRunInMode(cf::kCFRunLoopDefaultMode, STOP_CHECK_INTERVAL, 0);
+        }
+
+        fse::FSEventStreamStop(stream);
+        fse::FSEventStreamInvalidate(stream);
+        fse::FSEventStreamRelease(stream);
+        drop(Box::from_raw(context));
+    }
+}
+
+extern "C" fn callback(
+    _stream: fse::FSEventStreamRef,
+    info: *mut c_void,
+    num_events: usize,
+    event_paths: *mut c_void,
+    event_flags: *const fse::FSEventStreamEventFlags,
+    _event_ids: *const fse::FSEventStreamEventId,
+) {
+    let context = unsafe { &*(info as *const CallbackContext) };
+    let event_paths = event_paths as *const *const c_char;
+
+    for i in 0..num_events {
+        let (path, flags) = unsafe { (CStr::from_ptr(*event_paths.add(i)), *event_flags.add(i)) };
+        let path = OsStr::from_bytes(path.to_bytes()).to_os_string();
+        for event in translate_fsevents_flags(&context.roots, path, flags) {
+            // The receiver is only dropped right before the run loop is stopped.
+            context.sender.send(event).ok();
+        }
+    }
+}
+
+fn translate_fsevents_flags(
+    roots: &RefCell<BTreeSet<OsString>>,
+    path: OsString,
+    flags: fse::FSEventStreamEventFlags,
+) -> Vec<FileSystemEvent> {
+    let mut events = Vec::new();
+
+    if flags & fse::kFSEventStreamEventFlagRootChanged != 0 {
+        // A root directory was removed or moved away, no further events will be received for it.
+        let mut roots = roots.borrow_mut();
+        if !Path::new(&path).is_dir() && roots.remove(&path) {
+            if roots.is_empty() {
+                events.push(FileSystemEvent::Stopped(StopReason::DirectoryRemoved));
+            } else {
+                events.push(FileSystemEvent::RootStopped(
+                    path,
+                    StopReason::DirectoryRemoved,
+                ));
+            }
+        }
+        return events;
+    }
+
+    if flags
+        & (fse::kFSEventStreamEventFlagMustScanSubDirs
+            | fse::kFSEventStreamEventFlagUserDropped
+            | fse::kFSEventStreamEventFlagKernelDropped)
+        != 0
+    {
+        // Events were coalesced or dropped, so the subtree has to be rescanned.
+        events.push(FileSystemEvent::Overflow);
+        return events;
+    }
+
+    // FSEvents coalesces multiple changes of the same path into a single event, so a single
+    // callback can contain several flags. We check whether the path currently exists to decide
+    // about the order of creation and removal.
+    let is_dir = flags & fse::kFSEventStreamEventFlagItemIsDir != 0;
+    let exists = Path::new(&path).exists();
+    let created = if is_dir {
+        FileSystemEvent::DirectoryCreated(path.clone())
+    } else {
+        FileSystemEvent::FileCreated(path.clone(), None)
+    };
+    let removed = if is_dir {
+        FileSystemEvent::DirectoryRemoved(path.clone())
+    } else {
+        FileSystemEvent::FileRemoved(path.clone())
+    };
+
+    if flags & fse::kFSEventStreamEventFlagItemRenamed != 0 {
+        // FSEvents does not tell us which path was the source of a rename and which was the
+        // destination, but the destination is the one that exists.
+        if exists {
+            events.push(created);
+        } else {
+            events.push(removed);
+        }
+        return events;
+    }
+    if flags & fse::kFSEventStreamEventFlagItemCreated != 0 {
+        events.push(created);
+    }
+    if flags & fse::kFSEventStreamEventFlagItemModified != 0 && exists {
+        if is_dir {
+            events.push(FileSystemEvent::DirectoryModified(path.clone()));
+        } else {
+            events.push(FileSystemEvent::FileModified(path.clone(), None));
+        }
+    }
+    if flags & fse::kFSEventStreamEventFlagItemInodeMetaMod != 0 && exists && !is_dir {
+        events.push(FileSystemEvent::FileAttributesChanged(path.clone()));
+    }
+    if flags & fse::kFSEventStreamEventFlagItemRemoved != 0 && !exists {
+        events.push(removed);
+    }
+    events
+}
@@ -1,124 +1,376 @@
-use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::ffi::{OsStr, OsString};
 use std::fs;
+use std::io;
+use std::mem;
+use std::ops::Bound;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::Path;
 use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::task::{Context, Poll, Waker};
+use std::time::SystemTime;
 
+use futures::task;
+#[cfg(feature = "delay")]
+use futures::Future;
 use futures::Stream;
 use inotify::{EventMask, EventOwned, EventStream, Inotify, WatchDescriptor, WatchMask};
+use log::{debug, trace, warn};
+#[cfg(feature = "delay")]
+use tokio::time::{self, Delay};
 
-use super::{FileSystemEvent, StopReason};
+use super::{
+    Error, EventKindSet, FileMetadata, FileSystemEvent, PausePolicy, Stats, StopReason,
+    UnreadableDirectoryPolicy, WatcherOptions,
+};
+
+/// Minimum size of the inotify buffer - the buffer has to be able to hold at least one event with
+/// a file name of the maximum length.
+const MIN_BUFFER_SIZE: usize = 16 + 256;
 
 pub struct FileSystemWatcherInotify {
-    root_dir: OsString,
+    /// Root directories which have not been removed yet.
+    roots: BTreeSet<OsString>,
+    options: WatcherOptions,
     inotify: Inotify,
-    stream: Pin<Box<EventStream<InotifyBuffer>>>,
+    /// Stream of inotify events, created when the watcher is polled for the first time, so that
+    /// the watcher can be created outside of the tokio runtime which polls it.
+    stream: Option<Pin<Box<EventStream<InotifyBuffer>>>>,
+    /// Events read via read_events_into() which have not been translated yet. Set once the
+    /// watcher is driven without tokio, in which case the stream is never created.
+    read_events: Option<VecDeque<EventOwned>>,
     new_directories: BTreeSet<OsString>,
+    /// Directories in new_directories which were created after the initial scan and whose
+    /// contents are reported once they are watched (see `scan_on_create`).
+    created_directories: HashSet<OsString>,
+    /// Directories passed via `known_directories` which are watched without scanning them for
+    /// subdirectories.
+    unscanned_directories: HashSet<OsString>,
     watches_by_path: BTreeMap<OsString, WatchDescriptor>,
     paths_by_watch: HashMap<WatchDescriptor, OsString>,
-    //removed_watches: HashSet<WatchDescriptor>,
+    /// Watches which have been removed, but for which the IGNORED event has not been received
+    /// yet. As long as this set is not empty, we do not install new watches, as inotify could
+    /// otherwise reuse the watch descriptor while events for the old watch are still pending.
+    removed_watches: HashSet<WatchDescriptor>,
+    /// Synthetic events for existing directory contents and errors during directory listing or
+    /// watch removal which have not been returned yet.
+    queued_events: VecDeque<FileSystemEvent>,
+    /// MOVED_FROM events for which no MOVED_TO event has been received yet.
+    pending_moves: Vec<PendingMove>,
+    /// Roots which are files instead of directories.
+    file_roots: HashSet<OsString>,
+    /// Root directories which have been removed and for which the parent directory has to be
+    /// watched in order to notice when they are recreated (see `resilient_root`).
+    detached_roots: BTreeSet<OsString>,
+    /// Watches of the parent directories of removed root directories, with the root directories
+    /// which we are waiting for.
+    parent_watches: HashMap<WatchDescriptor, Vec<OsString>>,
+    /// Directories which could not be read and which are tried again once `retry_timer` expires
+    /// (see `UnreadableDirectoryPolicy::Retry`).
+    #[cfg(feature = "delay")]
+    unreadable_directories: BTreeSet<OsString>,
+    #[cfg(feature = "delay")]
+    retry_timer: Option<Pin<Box<Delay>>>,
+    /// Size and modification time of modified files, see `skip_unchanged_modifications`.
+    file_states: BTreeMap<OsString, FileState>,
+    /// Path of the last FileModified event if no other event has been reported since and the
+    /// inotify buffer has not been drained.
+    last_modified: Option<OsString>,
+    /// Set once ScanComplete has been reported.
+    scan_complete: bool,
+    /// Set when events or directories were dropped due to `max_queued_events`, until the
+    /// corresponding Overflow event has been returned.
+    overflow_pending: bool,
+    /// Set once WatchLimitWarning has been reported, until the number of watches drops below the
+    /// soft limit again.
+    watch_limit_reported: bool,
+    stats: Stats,
+    closed: bool,
+    /// Set while the watcher is paused, see `pause()`.
+    paused: Option<PausePolicy>,
+    /// Waker of the task which last polled the stream, used to report directories added via
+    /// watch().
+    waker: Option<Waker>,
+}
+
+#[derive(Clone, PartialEq)]
+struct FileState {
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+struct PendingMove {
+    cookie: u32,
+    path: OsString,
+    is_dir: bool,
 }
 
 impl FileSystemWatcherInotify {
-    pub fn new(path: &OsStr) -> Result<FileSystemWatcherInotify, super::Error> {
-        let mut inotify = Inotify::init()?;
-        let stream = inotify.event_stream(InotifyBuffer { data: [0; 1024] })?;
+    pub fn new(
+        roots: &[OsString],
+        mut options: WatcherOptions,
+    ) -> Result<FileSystemWatcherInotify, Error> {
+        for root in roots {
+            // Single files can be watched as well, but no other types of files.
+            match fs::metadata(root) {
+                Ok(metadata) if metadata.is_dir() || metadata.is_file() => {}
+                Ok(_) => return Err(Error::NotADirectory(root.clone())),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    return Err(Error::NotFound(root.clone()))
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let inotify = Inotify::init().map_err(Error::InotifyInit)?;
 
-        let mut new_directories = BTreeSet::new();
-        new_directories.insert(path.to_owned());
+        let roots = roots.iter().cloned().collect::<BTreeSet<_>>();
+        let mut new_directories = roots.clone();
+        let mut unscanned_directories = HashSet::new();
+        if let Some(known_directories) = options.known_directories.take() {
+            new_directories.extend(known_directories);
+            unscanned_directories.extend(new_directories.iter().cloned());
+        }
         Ok(FileSystemWatcherInotify {
-            root_dir: path.to_owned(),
-            inotify,
-            stream: Box::pin(stream),
             new_directories,
+            created_directories: HashSet::new(),
+            unscanned_directories,
+            roots,
+            options,
+            inotify,
+            stream: None,
+            read_events: None,
             watches_by_path: BTreeMap::new(),
             paths_by_watch: HashMap::new(),
+            removed_watches: HashSet::new(),
+            queued_events: VecDeque::new(),
+            pending_moves: Vec::new(),
+            file_roots: HashSet::new(),
+            detached_roots: BTreeSet::new(),
+            parent_watches: HashMap::new(),
+            #[cfg(feature = "delay")]
+            unreadable_directories: BTreeSet::new(),
+            #[cfg(feature = "delay")]
+            retry_timer: None,
+            file_states: BTreeMap::new(),
+            last_modified: None,
+            scan_complete: false,
+            overflow_pending: false,
+            watch_limit_reported: false,
+            stats: Stats::default(),
+            closed: false,
+            paused: None,
+            waker: None,
         })
     }
 
     fn translate_inotify_event(&mut self, inotify_event: EventOwned) -> Option<FileSystemEvent> {
         // TODO: Modify code to delete entries from new_directories as well.
 
+        trace!("inotify event: {:?}", inotify_event);
+        self.stats.raw_events += 1;
+
         if inotify_event.mask == EventMask::IGNORED {
-            // We manually deleted the watch or the directory was deleted. In any case, there is
-            // nothing to do here.
-            return None;
+            // We manually deleted the watch or the directory was deleted. In any case, no further
+            // events will be received for this watch descriptor.
+            self.removed_watches.remove(&inotify_event.wd);
+            return self.watch_invalidated(inotify_event.wd);
+        }
+        if inotify_event.mask == EventMask::Q_OVERFLOW {
+            // The event is not associated with any watch.
+            warn!("inotify event queue overflow");
+            return Some(FileSystemEvent::Overflow);
+        }
+        if self.parent_watches.contains_key(&inotify_event.wd) {
+            return self.translate_parent_event(inotify_event);
         }
         if !self.paths_by_watch.contains_key(&inotify_event.wd) {
             // We probably already deleted the watch. Ignore the event.
+            debug!("Event for unknown watch: {:?}", inotify_event);
             return None;
         }
 
         let directory = self.paths_by_watch.get(&inotify_event.wd).unwrap();
+        if self.file_roots.contains(directory) {
+            let path = directory.clone();
+            return self.translate_file_event(inotify_event, path);
+        }
         let mut path = directory.clone();
         let mut name_available = false;
         if let Some(name) = inotify_event.name.as_ref() {
-            path.push("/");
-            path.push(name);
+            path = Path::new(directory).join(name).into_os_string();
             name_available = true;
+
+            if self.options.is_ignored(Path::new(&path)) {
+                return None;
+            }
         }
 
-        // Translate the events. Note how we do not try to combine MOVED_FROM and MOVED_TO here.
-        // Per the man-page, there can be arbitrary numbers of other events inbetween, so combining
-        // the events is only possible if we wait some time. We could potentially reduce the CPU
-        // and I/O load caused by deleting and reestablishing all the watches for the
-        // subdirectories of a moved directory, but the code would become considerably more
-        // complex.
+        // Translate the events. MOVED_FROM events are stored until the MOVED_TO event with the
+        // same cookie arrives. Per the man-page, there can be arbitrary numbers of other events
+        // inbetween, and the MOVED_TO event is missing if the file was moved out of the watched
+        // tree. We therefore only wait until the inotify buffer has been drained and report the
+        // remaining MOVED_FROM events separately afterwards (see poll_inotify_stream()), so that
+        // FileEventDelay can still combine them if the MOVED_TO event arrives later. By default,
+        // the watches for a moved directory and its subdirectories are deleted and reestablished,
+        // unless rewrite_moved_paths is set (see rename_watches()).
         if inotify_event.mask == EventMask::CREATE && name_available {
-            Some(FileSystemEvent::FileCreated(path))
+            let metadata = file_metadata(&self.options, &path);
+            Some(FileSystemEvent::FileCreated(path, metadata))
         } else if inotify_event.mask == EventMask::MODIFY && name_available {
-            Some(FileSystemEvent::FileModified(path))
+            if !self.file_changed(&path) {
+                return None;
+            }
+            let metadata = file_metadata(&self.options, &path);
+            Some(FileSystemEvent::FileModified(path, metadata))
         } else if inotify_event.mask == EventMask::ATTRIB && name_available {
-            // TODO: Do we want a separate event type for this event?
-            Some(FileSystemEvent::FileModified(path))
+            if self.options.fold_attribute_changes {
+                let metadata = file_metadata(&self.options, &path);
+                Some(FileSystemEvent::FileModified(path, metadata))
+            } else {
+                Some(FileSystemEvent::FileAttributesChanged(path))
+            }
+        } else if inotify_event.mask == EventMask::CLOSE_WRITE && name_available {
+            Some(FileSystemEvent::FileWriteClosed(path))
+        } else if inotify_event.mask == EventMask::OPEN && name_available {
+            Some(FileSystemEvent::FileOpened(path))
+        } else if inotify_event.mask == EventMask::ACCESS && name_available {
+            Some(FileSystemEvent::FileAccessed(path))
+        } else if inotify_event.mask == EventMask::CLOSE_NOWRITE && name_available {
+            Some(FileSystemEvent::FileClosed(path))
+        } else if inotify_event.mask.contains(EventMask::ISDIR)
+            && inotify_event
+                .mask
+                .intersects(EventMask::OPEN | EventMask::ACCESS | EventMask::CLOSE_NOWRITE)
+        {
+            // Directories are opened and read whenever they are listed, including by the watcher
+            // itself, so we only report these events for files.
+            None
         } else if inotify_event.mask == EventMask::DELETE && name_available {
+            self.file_states.remove(&path);
             Some(FileSystemEvent::FileRemoved(path))
         } else if inotify_event.mask == EventMask::MOVED_FROM && name_available {
-            // TODO: Store the move cookie in the event so that it can later be combined with the
-            // MOVED_TO event.
-            Some(FileSystemEvent::FileRemoved(path))
+            self.file_states.remove(&path);
+            self.push_pending_move(PendingMove {
+                cookie: inotify_event.cookie,
+                path,
+                is_dir: false,
+            });
+            None
         } else if inotify_event.mask == EventMask::MOVED_TO && name_available {
-            // TODO: Store the move cookie in the event so that it can later be combined with the
-            // MOVED_TO event.
-            Some(FileSystemEvent::FileCreated(path))
+            self.file_states.remove(&path);
+            match self.take_pending_move(inotify_event.cookie) {
+                Some(from) => Some(FileSystemEvent::FileMoved(from.path, path)),
+                None => Some(FileSystemEvent::MovedTo {
+                    path,
+                    cookie: inotify_event.cookie,
+                    is_dir: false,
+                }),
+            }
         } else if inotify_event.mask == EventMask::CREATE | EventMask::ISDIR && name_available {
-            // Start monitoring the directory as well.
-            // We do not generate events for existing contents of the directory - the caller just
-            // is notified that we started monitoring the directory and has to detect changes
-            // themselves. The same logic is already required during initialization.
-            self.new_directories.insert(path.clone());
+            // Start monitoring the directory as well, unless only the root directory is watched or
+            // the directory is too deep. We do not generate events for existing contents of the
+            // directory - the caller just is notified that we started monitoring the directory and
+            // has to detect changes themselves. The same logic is already required during
+            // initialization.
+            if self.is_watched_subdirectory(&path) {
+                if self.queue_directory(path.clone()) && self.options.scan_on_create {
+                    self.created_directories.insert(path.clone());
+                }
+            }
             Some(FileSystemEvent::DirectoryCreated(path))
+        } else if inotify_event.mask == EventMask::ATTRIB | EventMask::ISDIR && name_available {
+            // The metadata of a subdirectory changed. We use the event reported to the watch of
+            // the parent directory, as inotify does not report any name-less events for
+            // subdirectories which are not watched (e.g., in non-recursive mode).
+            Some(FileSystemEvent::DirectoryModified(path))
         } else if inotify_event.mask == EventMask::DELETE | EventMask::ISDIR && name_available {
             self.delete_watches(&path);
             Some(FileSystemEvent::DirectoryRemoved(path))
         } else if inotify_event.mask == EventMask::MOVED_FROM | EventMask::ISDIR && name_available {
-            self.delete_watches(&path);
-            // TODO: Store the move cookie in the event so that it can later be combined with the
-            // MOVED_TO event.
-            Some(FileSystemEvent::DirectoryRemoved(path))
+            // If the watches are kept, they are only removed once we know that the directory was
+            // moved out of the watched tree (see flush_pending_move()).
+            if !self.options.rewrite_moved_paths {
+                self.delete_watches(&path);
+            }
+            self.push_pending_move(PendingMove {
+                cookie: inotify_event.cookie,
+                path,
+                is_dir: true,
+            });
+            None
         } else if inotify_event.mask == EventMask::MOVED_TO | EventMask::ISDIR && name_available {
-            // Start monitoring the directory as well.
+            // Start monitoring the directory as well, unless only the root directory is watched.
             // We do not generate events for existing contents of the directory - the caller just
             // is notified that we started monitoring the directory and has to detect changes
             // themselves. The same logic is already required during initialization.
-            self.new_directories.insert(path.clone());
-            // TODO: Store the move cookie in the event so that it can later be combined with the
-            // MOVED_TO event.
-            Some(FileSystemEvent::DirectoryCreated(path))
+            match self.take_pending_move(inotify_event.cookie) {
+                Some(from) => {
+                    if self.options.rewrite_moved_paths {
+                        self.rename_watches(&from.path, &path);
+                    } else if self.is_watched_subdirectory(&path) {
+                        self.queue_directory(path.clone());
+                    }
+                    Some(FileSystemEvent::DirectoryMoved(from.path, path))
+                }
+                None => {
+                    if self.is_watched_subdirectory(&path) {
+                        self.queue_directory(path.clone());
+                    }
+                    Some(FileSystemEvent::MovedTo {
+                        path,
+                        cookie: inotify_event.cookie,
+                        is_dir: true,
+                    })
+                }
+            }
+        } else if inotify_event
+            .mask
+            .intersects(EventMask::ATTRIB | EventMask::MODIFY)
+            && !name_available
+        {
+            // The watched directory itself was modified. For subdirectories, the change has
+            // already been reported via the event for the watch of the parent directory, but
+            // there is no such watch for the root directories.
+            if self.roots.contains(&path) {
+                Some(FileSystemEvent::DirectoryModified(path))
+            } else {
+                None
+            }
         } else if inotify_event.mask == EventMask::DELETE_SELF {
-            // If this event is not about the root directory, we already generated an event for it
+            // If this event is not about a root directory, we already generated an event for it
             // when we received DELETE. Else, notify the user that the root directory was deleted
-            // and no further events will be received.
-            if path == self.root_dir {
+            // and no further events will be received for it - unless we shall wait for the root
+            // directory to be recreated.
+            if !self.roots.contains(&path) {
+                None
+            } else if self.options.resilient_root {
                 self.delete_watches(&path);
-                Some(FileSystemEvent::Stopped(StopReason::DirectoryRemoved))
+                self.detached_roots.insert(path.clone());
+                Some(FileSystemEvent::DirectoryRemoved(path))
             } else {
+                self.delete_watches(&path);
+                Some(self.stop_root(path, StopReason::DirectoryRemoved))
+            }
+        } else if inotify_event.mask == EventMask::MOVE_SELF {
+            // Subdirectories which were moved have already been handled when we received
+            // MOVED_FROM for the parent directory, and their watches have been removed. A moved
+            // root directory can no longer be reached via its path, so we stop watching it, or
+            // wait for a new directory at the same path.
+            if !self.roots.contains(&path) {
                 None
+            } else if self.options.resilient_root {
+                self.delete_watches(&path);
+                self.detached_roots.insert(path.clone());
+                Some(FileSystemEvent::DirectoryRemoved(path))
+            } else {
+                self.delete_watches(&path);
+                Some(self.stop_root(path, StopReason::DirectoryMoved))
             }
         } else {
-            eprintln!(
-                "Warning: Unexpected inotify event: {}, {:?}",
+            warn!(
+                "Unexpected inotify event: {}, {:?}",
                 directory.to_string_lossy(),
                 inotify_event
             );
@@ -126,7 +378,458 @@ impl FileSystemWatcherInotify {
         }
     }
 
-    fn watch_subdirectories(&mut self, path: &OsStr) {
+    /// Handles an event for a root which is a file.
+    fn translate_file_event(
+        &mut self,
+        inotify_event: EventOwned,
+        path: OsString,
+    ) -> Option<FileSystemEvent> {
+        if inotify_event.mask.contains(EventMask::MODIFY) {
+            if !self.file_changed(&path) {
+                return None;
+            }
+            let metadata = file_metadata(&self.options, &path);
+            Some(FileSystemEvent::FileModified(path, metadata))
+        } else if inotify_event.mask.contains(EventMask::ATTRIB) {
+            if self.options.fold_attribute_changes {
+                let metadata = file_metadata(&self.options, &path);
+                Some(FileSystemEvent::FileModified(path, metadata))
+            } else {
+                Some(FileSystemEvent::FileAttributesChanged(path))
+            }
+        } else if inotify_event.mask.contains(EventMask::CLOSE_WRITE) {
+            Some(FileSystemEvent::FileWriteClosed(path))
+        } else if inotify_event.mask.contains(EventMask::OPEN) {
+            Some(FileSystemEvent::FileOpened(path))
+        } else if inotify_event.mask.contains(EventMask::ACCESS) {
+            Some(FileSystemEvent::FileAccessed(path))
+        } else if inotify_event.mask.contains(EventMask::CLOSE_NOWRITE) {
+            Some(FileSystemEvent::FileClosed(path))
+        } else if inotify_event
+            .mask
+            .intersects(EventMask::DELETE_SELF | EventMask::MOVE_SELF)
+        {
+            // We cannot tell where the file was moved to, so moving the file is treated like
+            // removal.
+            self.delete_watches(&path);
+            self.file_roots.remove(&path);
+            let stopped = self.stop_root(path.clone(), StopReason::DirectoryRemoved);
+            self.queued_events.push_back(stopped);
+            Some(FileSystemEvent::FileRemoved(path))
+        } else {
+            None
+        }
+    }
+
+    /// Handles an event for the parent directory of a removed root directory.
+    fn translate_parent_event(&mut self, inotify_event: EventOwned) -> Option<FileSystemEvent> {
+        let wd = inotify_event.wd;
+        if inotify_event.mask.contains(EventMask::DELETE_SELF) {
+            // The parent directory was removed as well, so we give up on the root directories.
+            for root in self.parent_watches.remove(&wd).unwrap() {
+                if self.roots.contains(&root) {
+                    let event = self.stop_root(root, StopReason::DirectoryRemoved);
+                    self.queued_events.push_back(event);
+                }
+            }
+            return self.queued_events.pop_front();
+        }
+
+        if !inotify_event.mask.contains(EventMask::ISDIR)
+            || !inotify_event
+                .mask
+                .intersects(EventMask::CREATE | EventMask::MOVED_TO)
+        {
+            return None;
+        }
+        let name = inotify_event.name.as_ref()?;
+        let waiting = self.parent_watches.get_mut(&wd).unwrap();
+        let index = waiting
+            .iter()
+            .position(|root| Path::new(root).file_name() == Some(name.as_os_str()))?;
+        let root = waiting.remove(index);
+        if waiting.is_empty() {
+            self.remove_parent_watch(wd);
+        }
+        if self.roots.contains(&root) {
+            // The root directory was recreated, so we watch it again.
+            self.new_directories.insert(root);
+        }
+        None
+    }
+
+    /// Watches the parent directory of a removed root directory, so that we notice when the root
+    /// directory is recreated.
+    fn watch_parent(&mut self, root: OsString) -> Option<FileSystemEvent> {
+        if !self.roots.contains(&root) {
+            // The root directory was removed via unwatch() in the meantime.
+            return None;
+        }
+        let parent = match Path::new(&root).parent() {
+            Some(parent) => parent.as_os_str().to_owned(),
+            None => return Some(self.stop_root(root, StopReason::DirectoryRemoved)),
+        };
+        // We use the same mask as for other watches, as inotify replaces the mask if the parent
+        // directory is already being watched.
+        let wd = match self.inotify.add_watch(&parent, watch_mask(&self.options)) {
+            Ok(wd) => wd,
+            Err(e) => {
+                warn!(
+                    "Cannot watch {} for recreation of {}: {:?}",
+                    parent.to_string_lossy(),
+                    root.to_string_lossy(),
+                    e
+                );
+                return Some(self.stop_root(root, StopReason::DirectoryRemoved));
+            }
+        };
+        if Path::new(&root).is_dir() {
+            // The directory was recreated before we installed the watch.
+            if !self.paths_by_watch.contains_key(&wd) && !self.parent_watches.contains_key(&wd) {
+                self.remove_parent_watch(wd);
+            }
+            self.new_directories.insert(root);
+        } else if !self.paths_by_watch.contains_key(&wd) {
+            self.parent_watches.entry(wd).or_default().push(root);
+        } else {
+            // The parent directory is watched anyways, so the recreated root directory is watched
+            // like any other new subdirectory.
+        }
+        None
+    }
+
+    /// Handles an IGNORED event for a watch which we did not remove ourselves.
+    ///
+    /// Usually, the watches are already removed when the removal of the directory is reported to
+    /// the watch of the parent directory. The kernel can also invalidate a watch without such an
+    /// event, e.g., if the directory is the target of a followed symlink and is located outside
+    /// of the watched tree or if its file system was unmounted. The directory can no longer be
+    /// monitored, so it is treated as removed.
+    fn watch_invalidated(&mut self, wd: WatchDescriptor) -> Option<FileSystemEvent> {
+        let path = self.paths_by_watch.get(&wd)?.clone();
+        debug!(
+            "Watch for {} removed without prior removal event",
+            path.to_string_lossy()
+        );
+        self.delete_watches(&path);
+        if self.roots.contains(&path) {
+            Some(self.stop_root(path, StopReason::DirectoryRemoved))
+        } else {
+            Some(FileSystemEvent::DirectoryRemoved(path))
+        }
+    }
+
+    fn remove_parent_watch(&mut self, wd: WatchDescriptor) {
+        self.parent_watches.remove(&wd);
+        if self.inotify.rm_watch(wd.clone()).is_ok() {
+            self.removed_watches.insert(wd);
+        }
+    }
+
+    /// Removes a root directory and returns the event which notifies the user about it.
+    fn stop_root(&mut self, root: OsString, reason: StopReason) -> FileSystemEvent {
+        self.roots.remove(&root);
+        if self.roots.is_empty() {
+            FileSystemEvent::Stopped(reason)
+        } else {
+            FileSystemEvent::RootStopped(root, reason)
+        }
+    }
+
+    /// Removes all watches. Afterwards, the stream does not return any further events.
+    ///
+    /// Calling this function multiple times has no effect.
+    pub fn close(&mut self) {
+        for (_, wd) in mem::replace(&mut self.watches_by_path, BTreeMap::new()) {
+            // As in delete_watches(), the watch might already be invalid.
+            self.inotify.rm_watch(wd).ok();
+            self.stats.watches_removed += 1;
+        }
+        for (wd, _) in mem::replace(&mut self.parent_watches, HashMap::new()) {
+            self.inotify.rm_watch(wd).ok();
+        }
+        self.file_roots.clear();
+        self.unscanned_directories.clear();
+        self.detached_roots.clear();
+        #[cfg(feature = "delay")]
+        {
+            self.unreadable_directories.clear();
+            self.retry_timer = None;
+        }
+        self.paths_by_watch.clear();
+        self.removed_watches.clear();
+        self.new_directories.clear();
+        self.created_directories.clear();
+        self.queued_events.clear();
+        self.pending_moves.clear();
+        self.file_states.clear();
+        self.overflow_pending = false;
+        self.closed = true;
+    }
+
+    /// Returns the events which are still buffered without installing any new watches, and
+    /// returns `None` once the buffer has been drained.
+    pub fn poll_remaining(&mut self, cx: &mut Context) -> Poll<Option<FileSystemEvent>> {
+        loop {
+            if self.closed {
+                return Poll::Ready(None);
+            }
+            if self.overflow_pending {
+                self.overflow_pending = false;
+                return Poll::Ready(Some(FileSystemEvent::Overflow));
+            }
+            let event = match self.queued_events.pop_front() {
+                Some(event) => event,
+                None => match self.poll_inotify_stream(cx) {
+                    Poll::Ready(Some(event)) => event,
+                    _ => return Poll::Ready(None),
+                },
+            };
+            if let Some(event) = self.finish_event(event) {
+                return Poll::Ready(Some(event));
+            }
+        }
+    }
+
+    /// Applies the options which affect all events right before an event is returned and counts
+    /// the event. Returns `None` if the event was not requested.
+    fn finish_event(&mut self, mut event: FileSystemEvent) -> Option<FileSystemEvent> {
+        // inotify cannot filter events by type for directories and files separately, so we still
+        // receive some events which were not requested.
+        if !self.options.events.matches(&event) {
+            return None;
+        }
+        if self.options.strict_paths {
+            if let Some(path) = event.non_utf8_path().map(|p| p.as_os_str().to_owned()) {
+                event = FileSystemEvent::NonUtf8Path(path, event.kind());
+            }
+        }
+        *self.stats.events.entry(event.kind()).or_insert(0) += 1;
+        Some(event)
+    }
+
+    /// Starts monitoring an additional directory (and, if recursive, its subdirectories).
+    ///
+    /// A `DirectoryWatched` event is generated once the watch has been installed.
+    pub fn watch(&mut self, path: &OsStr) {
+        // Directories outside of the existing roots become roots themselves, so that their
+        // removal is reported.
+        if !self
+            .roots
+            .iter()
+            .any(|root| Path::new(path).starts_with(root))
+        {
+            self.roots.insert(path.to_owned());
+        }
+        self.new_directories.insert(path.to_owned());
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Walks the whole tree again to install missing watches and, if `emit_existing` is set,
+    /// reports the existing contents again.
+    pub fn rescan(&mut self) {
+        self.new_directories.extend(self.roots.iter().cloned());
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Stops delivering events and installing watches until `resume()` is called.
+    pub fn pause(&mut self, policy: PausePolicy) {
+        self.paused = Some(policy);
+    }
+
+    /// Continues delivering events after `pause()`.
+    pub fn resume(&mut self) {
+        self.paused = None;
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Stops monitoring a directory and all its subdirectories.
+    pub fn unwatch(&mut self, path: &OsStr) {
+        self.delete_watches(path);
+        let removed_roots = self
+            .roots
+            .iter()
+            .filter(|root| Path::new(root).starts_with(path))
+            .cloned()
+            .collect::<Vec<_>>();
+        for root in removed_roots {
+            self.roots.remove(&root);
+        }
+    }
+
+    /// Returns the directories for which inotify watches are currently installed.
+    pub fn watched_paths(&self) -> Vec<OsString> {
+        self.watches_by_path.keys().cloned().collect()
+    }
+
+    /// Returns the counters collected since the watcher was created.
+    pub fn stats(&self) -> Stats {
+        self.stats.clone()
+    }
+
+    /// Returns the number of installed inotify watches.
+    pub fn watch_count(&self) -> usize {
+        self.watches_by_path.len() + self.parent_watches.len()
+    }
+
+    /// Reports a WatchLimitWarning event once the number of watches exceeds the soft limit.
+    fn check_watch_limit(&mut self) {
+        let limit = match self.options.soft_watch_limit {
+            Some(limit) => limit,
+            None => return,
+        };
+        let count = self.watch_count();
+        if count <= limit {
+            self.watch_limit_reported = false;
+        } else if !self.watch_limit_reported {
+            warn!(
+                "{} inotify watches installed, soft limit is {}",
+                count, limit
+            );
+            self.watch_limit_reported = true;
+            self.queued_events
+                .push_back(FileSystemEvent::WatchLimitWarning(count));
+        }
+    }
+
+    /// Returns the number of directories for which watches still have to be installed.
+    pub fn pending_watch_count(&self) -> usize {
+        self.new_directories.len()
+    }
+
+    /// Returns whether `max_queued_events` entries are already queued.
+    fn queue_full(&self, queued: usize) -> bool {
+        match self.options.max_queued_events {
+            Some(max_queued_events) => queued >= max_queued_events,
+            None => false,
+        }
+    }
+
+    /// Counts an event or directory which was dropped because the queue was full and makes sure
+    /// that an Overflow event is reported.
+    fn drop_queued(&mut self) {
+        if !self.overflow_pending {
+            warn!(
+                "watcher queue overflow, limit is {:?}",
+                self.options.max_queued_events
+            );
+        }
+        self.stats.dropped_events += 1;
+        self.overflow_pending = true;
+    }
+
+    /// Queues an event generated while listing a directory, unless `max_queued_events` is
+    /// reached.
+    fn queue_event(&mut self, event: FileSystemEvent) {
+        if self.queue_full(self.queued_events.len()) {
+            self.drop_queued();
+        } else {
+            self.queued_events.push_back(event);
+        }
+    }
+
+    /// Enters a directory into new_directories, unless `max_queued_events` is reached. Returns
+    /// false if the directory was dropped.
+    fn queue_directory(&mut self, path: OsString) -> bool {
+        if self.new_directories.contains(&path) {
+            return true;
+        }
+        if self.queue_full(self.new_directories.len()) {
+            self.drop_queued();
+            return false;
+        }
+        self.new_directories.insert(path);
+        true
+    }
+
+    /// Stores a MOVED_FROM event until the matching MOVED_TO event arrives. If
+    /// `max_queued_events` moves are already pending, the oldest one is reported right away.
+    fn push_pending_move(&mut self, pending_move: PendingMove) {
+        if self.queue_full(self.pending_moves.len()) {
+            if let Some(event) = self.flush_pending_move() {
+                self.queued_events.push_back(event);
+            }
+        }
+        self.pending_moves.push(pending_move);
+    }
+
+    fn take_pending_move(&mut self, cookie: u32) -> Option<PendingMove> {
+        let index = self.pending_moves.iter().position(|m| m.cookie == cookie)?;
+        Some(self.pending_moves.remove(index))
+    }
+
+    /// Reports the oldest MOVED_FROM event without a matching MOVED_TO event.
+    fn flush_pending_move(&mut self) -> Option<FileSystemEvent> {
+        if self.pending_moves.is_empty() {
+            return None;
+        }
+        let pending = self.pending_moves.remove(0);
+        if pending.is_dir && self.options.rewrite_moved_paths {
+            // The watches were kept in case the directory was moved within the watched tree.
+            self.delete_watches(&pending.path);
+        }
+        Some(FileSystemEvent::MovedFrom {
+            path: pending.path,
+            cookie: pending.cookie,
+            is_dir: pending.is_dir,
+        })
+    }
+
+    /// Returns whether a subdirectory shall be monitored, depending on the `recursive` and
+    /// `max_depth` options.
+    fn is_watched_subdirectory(&self, path: &OsStr) -> bool {
+        if !self.options.recursive || self.options.is_excluded(Path::new(path)) {
+            return false;
+        }
+        let max_depth = match self.options.max_depth {
+            Some(max_depth) => max_depth,
+            None => return true,
+        };
+        // The depth is relative to the closest root directory containing the path.
+        let depth = self
+            .roots
+            .iter()
+            .filter_map(|root| Path::new(path).strip_prefix(root).ok())
+            .map(|relative| relative.components().count())
+            .min()
+            .unwrap_or(0);
+        depth <= max_depth
+    }
+
+    /// Returns whether the directory is located on a different file system than its parent
+    /// directory and must not be watched (see `cross_fs`).
+    fn is_filesystem_boundary(&self, parent_device: Option<u64>, path: &OsStr) -> bool {
+        let parent_device = match parent_device {
+            Some(device) => device,
+            None => return false,
+        };
+        match fs::metadata(path) {
+            Ok(metadata) if metadata.dev() != parent_device => {
+                debug!(
+                    "Skipping {}, which is on a different file system",
+                    path.to_string_lossy()
+                );
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Enters the subdirectories of a directory into new_directories and, if `emit_existing` is
+    /// set, reports its contents.
+    fn watch_subdirectories(&mut self, path: &OsStr, emit_existing: bool) {
+        // The device of the directory is only needed to detect mount points below it.
+        let device = if self.options.cross_fs {
+            None
+        } else {
+            fs::metadata(path).map(|metadata| metadata.dev()).ok()
+        };
         match fs::read_dir(&path) {
             Ok(entries) => {
                 for entry in entries {
@@ -134,186 +837,815 @@ impl FileSystemWatcherInotify {
                         Ok(entry) => {
                             match entry.file_type() {
                                 Ok(file_type) => {
-                                    if file_type.is_dir() {
-                                        let new_dir =
-                                            entry.path().as_path().as_os_str().to_os_string();
-                                        self.new_directories.insert(new_dir);
+                                    // DirEntry::file_type() does not follow symlinks, so we
+                                    // have to check the target if requested.
+                                    let is_dir = file_type.is_dir()
+                                        || (self.options.follow_symlinks
+                                            && file_type.is_symlink()
+                                            && entry.path().is_dir());
+                                    let entry_path =
+                                        entry.path().as_path().as_os_str().to_os_string();
+                                    if self.options.is_ignored(&entry.path()) {
+                                        // Ignored directories are not watched at all.
+                                    } else if is_dir {
+                                        if emit_existing {
+                                            self.queue_event(FileSystemEvent::DirectoryCreated(
+                                                entry_path.clone(),
+                                            ));
+                                        }
+                                        if self.is_watched_subdirectory(&entry_path)
+                                            && !self.is_filesystem_boundary(device, &entry_path)
+                                            && self.queue_directory(entry_path.clone())
+                                        {
+                                            // The contents of subdirectories of a new directory
+                                            // are new as well.
+                                            if emit_existing && self.options.scan_on_create {
+                                                self.created_directories.insert(entry_path);
+                                            }
+                                        }
+                                    } else if emit_existing {
+                                        let metadata = file_metadata(&self.options, &entry_path);
+                                        self.queue_event(FileSystemEvent::FileCreated(
+                                            entry_path, metadata,
+                                        ));
                                     }
                                 }
-                                Err(e) => eprintln!(
-                                    "Warning: Cannot determine file type of {}: {:?}",
-                                    entry.path().as_path().to_str().unwrap_or("(non-UTF path)"),
-                                    e
-                                ),
+                                Err(e) => {
+                                    // We cannot tell whether the entry is a directory which
+                                    // needs to be watched.
+                                    self.queue_event(FileSystemEvent::WatchFailed(
+                                        entry.path().into_os_string(),
+                                        e.into(),
+                                    ))
+                                }
                             };
                         }
-                        Err(e) => eprintln!(
-                            "Warning: Error during directory listing of {}: {:?}",
-                            path.to_string_lossy(),
-                            e
-                        ),
+                        Err(e) => self
+                            .queue_event(FileSystemEvent::WatchFailed(path.to_owned(), e.into())),
                     };
                 }
             }
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                // The directory is not readable due to (intentionally set) access rights, so its
+                // subdirectories are not monitored.
+                if let Some(event) = self.unreadable_directory(path.to_owned(), e.into()) {
+                    self.queued_events.push_back(event);
+                }
+            }
             Err(e) => {
-                // TODO: Should we do anything here? The directory is most likely not
-                // readable due to (intentionally set) access rights.
-                eprintln!(
-                    "Warning: Cannot monitor directory {}: {:?}",
-                    path.to_string_lossy(),
-                    e
-                );
+                self.queued_events
+                    .push_back(FileSystemEvent::WatchFailed(path.to_owned(), e.into()));
+            }
+        };
+    }
+
+    /// Handles a directory which could not be watched or listed due to missing permissions
+    /// according to `UnreadableDirectoryPolicy`.
+    fn unreadable_directory(&mut self, path: OsString, error: Error) -> Option<FileSystemEvent> {
+        match self.options.unreadable_directories {
+            UnreadableDirectoryPolicy::Skip => {
+                debug!("Skipping unreadable directory {}", path.to_string_lossy());
+                None
+            }
+            UnreadableDirectoryPolicy::Report => Some(FileSystemEvent::WatchFailed(path, error)),
+            #[cfg(feature = "delay")]
+            UnreadableDirectoryPolicy::Retry(delay) => {
+                // All directories which fail until the timer expires are retried at once.
+                self.unreadable_directories.insert(path.clone());
+                if self.retry_timer.is_none() {
+                    self.retry_timer = Some(Box::pin(time::delay_for(delay)));
+                }
+                Some(FileSystemEvent::WatchFailed(path, error))
+            }
+        }
+    }
+
+    /// Returns whether the size or the modification time of a modified file changed since the
+    /// last modification, if `skip_unchanged_modifications` is set.
+    fn file_changed(&mut self, path: &OsStr) -> bool {
+        if !self.options.skip_unchanged_modifications {
+            return true;
+        }
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                // The file has already been removed, the removal is reported separately.
+                self.file_states.remove(path);
+                return true;
             }
         };
+        let state = FileState {
+            size: metadata.size(),
+            modified: metadata.modified().ok(),
+        };
+        self.file_states.insert(path.to_owned(), state.clone()) != Some(state)
     }
 
     fn delete_watches(&mut self, path: &OsStr) {
-        // Remove watches for the directory and for all subdirectories.
-        let mut watches_to_delete = Vec::new();
-        let mut path_iter = self.watches_by_path.range(path.to_owned()..);
+        // Remove watches for the directory and for all subdirectories. We only look up the watch
+        // descriptor in the range and take the owned path from paths_by_watch, so that no paths
+        // have to be cloned. The search continues after the last removed path, so entries which
+        // are not below the path (see paths_below()) are only skipped once.
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!(
+            "delete_watches",
+            path = %path.to_string_lossy(),
+            subtree_size = tracing::field::Empty
+        );
+        #[cfg(feature = "tracing")]
+        let _enter = span.enter();
+        #[cfg(feature = "tracing")]
+        let watches_removed = self.stats.watches_removed;
+
+        let mut previous: Option<OsString> = None;
         loop {
-            match path_iter.next() {
+            let start = match &previous {
+                Some(p) => Bound::Excluded(p.as_os_str()),
+                None => Bound::Included(path),
+            };
+            let wd = match first_below(
+                path,
+                self.watches_by_path
+                    .range::<OsStr, _>((start, Bound::Unbounded)),
+            ) {
+                Some(wd) => wd.clone(),
                 None => break,
-                Some((p, wd)) => {
-                    let p_path: &Path = p.as_ref();
-                    if p_path.starts_with(path) {
-                        watches_to_delete.push((p.clone(), wd.clone()));
-                    } else {
-                        // The paths are sorted, so the first entry that does not start with the
-                        // path passed to this function limits the subdirectories.
-                        break;
-                    }
+            };
+            let p = self.paths_by_watch.remove(&wd).unwrap();
+            let removed = self.watches_by_path.remove(&p);
+            debug_assert!(removed.is_some());
+            trace!("rm_watch: {}", p.to_string_lossy());
+            self.stats.watches_removed += 1;
+            // If removal succeeded, we have to wait for the IGNORED event before we can install
+            // new watches. EINVAL is ignored, because the IGNORED event for the watch might
+            // already be in the inotify buffer (meaning that the watch is already invalid), we
+            // just did not read and process it yet.
+            match self.inotify.rm_watch(wd.clone()) {
+                Ok(()) => {
+                    self.removed_watches.insert(wd);
+                }
+                Err(ref e) if e.raw_os_error() == Some(libc::EINVAL) => {}
+                Err(e) => {
+                    self.queued_events
+                        .push_back(FileSystemEvent::Error(Error::RemoveWatch {
+                            path: p.clone(),
+                            source: e,
+                        }))
                 }
             }
+            previous = Some(p);
         }
-        for (p, wd) in watches_to_delete.into_iter() {
-            self.watches_by_path.remove(&p);
-            self.paths_by_watch.remove(&wd);
-            // We ignore errors here, because the IGNORED event for the watch might already be in
-            // the inotify buffer (meaning that the watch is already invalid), we just did not read
-            // and process it yet.
-            self.inotify.rm_watch(wd).ok();
-        }
+        #[cfg(feature = "tracing")]
+        span.record(
+            "subtree_size",
+            &(self.stats.watches_removed - watches_removed),
+        );
         // We also have to delete the entries from new_directories, as watches are only added once
         // the inotify buffer has been drained. We do not want to accidently add watches for these
         // deleted directories if the user concurrently creates a new directory.
         //
         // This has to be as complicated as is, because new_directories might contain an arbitrary
         // number of subdirectories of an (already watched) directory.
-        let mut new_dirs_to_delete = Vec::new();
-        let mut path_iter = self.new_directories.range(path.to_owned()..);
-        loop {
-            match path_iter.next() {
-                None => break,
-                Some(p) => {
-                    let p_path: &Path = p.as_ref();
-                    if p_path.starts_with(path) {
-                        new_dirs_to_delete.push(p.clone());
-                    } else {
-                        // The paths are sorted, so the first entry that does not start with the
-                        // path passed to this function limits the subdirectories.
+        let new_dirs_to_delete = paths_below(path, self.new_directories.range(path.to_owned()..));
+        for p in new_dirs_to_delete.into_iter() {
+            self.created_directories.remove(&p);
+            self.new_directories.remove(&p);
+        }
+        if !self.file_states.is_empty() {
+            let files = paths_below(
+                path,
+                self.file_states
+                    .range::<OsStr, _>((Bound::Included(path), Bound::Unbounded))
+                    .map(|(p, _)| p),
+            );
+            for p in files {
+                self.file_states.remove(&p);
+            }
+        }
+    }
+
+    /// Returns the event as reported by inotify. The path is empty if the event does not belong
+    /// to a watched directory or file.
+    fn raw_event(&self, inotify_event: &EventOwned) -> FileSystemEvent {
+        let mut path = self
+            .paths_by_watch
+            .get(&inotify_event.wd)
+            .cloned()
+            .unwrap_or_default();
+        if let Some(name) = inotify_event.name.as_ref() {
+            path = Path::new(&path).join(name).into_os_string();
+        }
+        FileSystemEvent::Raw {
+            path,
+            mask: inotify_event.mask.bits(),
+            cookie: inotify_event.cookie,
+        }
+    }
+
+    /// Replaces the path prefix `from` with `to` for all watches below a moved directory, so that
+    /// the watch descriptors stay valid and no watches need to be reinstalled.
+    fn rename_watches(&mut self, from: &OsStr, to: &OsStr) {
+        // If the directory replaced an empty directory, the watch of the latter is stale.
+        self.delete_watches(to);
+
+        let moved_watches = paths_below(from, self.watches_by_path.range(from.to_owned()..));
+        for old in moved_watches.into_iter() {
+            let new = rename_path(&old, from, to);
+            trace!(
+                "rename watch: {} -> {}",
+                old.to_string_lossy(),
+                new.to_string_lossy()
+            );
+            let wd = self.watches_by_path.remove(&old).unwrap();
+            self.paths_by_watch.insert(wd.clone(), new.clone());
+            self.watches_by_path.insert(new, wd);
+        }
+        // Directories which have not been watched yet have to be watched at their new location.
+        let moved_new_dirs = paths_below(from, self.new_directories.range(from.to_owned()..));
+        for old in moved_new_dirs.into_iter() {
+            self.new_directories.remove(&old);
+            self.new_directories.insert(rename_path(&old, from, to));
+        }
+    }
+
+    /// Returns the file descriptor of the inotify instance.
+    pub fn as_raw_fd(&self) -> RawFd {
+        self.inotify.as_raw_fd()
+    }
+
+    /// Returns whether the watcher has been polled as a stream, in which case the file descriptor
+    /// is registered with the reactor of a tokio runtime.
+    pub fn uses_event_stream(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Reads the available events from the inotify file descriptor without blocking and returns
+    /// the translated events, see `FileSystemWatcher::read_events_into()`.
+    pub fn read_events_into(&mut self, buffer: &mut [u8]) -> Result<Vec<FileSystemEvent>, Error> {
+        if self.uses_event_stream() {
+            // The event stream reads from the same file descriptor, so the events would be split
+            // between two readers and moves would be torn apart.
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "the watcher has already been polled as a stream",
+            )
+            .into());
+        }
+        let queue = self.read_events.get_or_insert_with(VecDeque::new);
+        while !self.closed {
+            // The file descriptor is non-blocking, so no events are returned once the kernel
+            // queue has been drained. We read until then, as a single buffer might not hold all
+            // available events.
+            match self.inotify.read_events(buffer) {
+                Ok(events) => {
+                    let queued = queue.len();
+                    queue.extend(events.map(|event| event.into_owned()));
+                    if queue.len() == queued {
                         break;
                     }
                 }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if is_transient(e) => {}
+                Err(e) => {
+                    // As in poll_inotify_stream(), no further events can be read.
+                    self.close();
+                    return Err(e.into());
+                }
             }
         }
-        for p in new_dirs_to_delete.into_iter() {
-            self.new_directories.remove(&p);
+        // Nobody needs to be woken up, the caller calls us again once the file descriptor is
+        // readable.
+        let mut cx = Context::from_waker(task::noop_waker_ref());
+        let mut events = Vec::new();
+        while let Poll::Ready(Some(event)) = self.poll_translated(&mut cx) {
+            events.push(event);
+        }
+        Ok(events)
+    }
+
+    /// Returns the next translated event, used both by the stream and by read_events_into().
+    fn poll_translated(&mut self, cx: &mut Context) -> Poll<Option<FileSystemEvent>> {
+        #[cfg(feature = "tracing")]
+        let span = tracing::trace_span!("poll", raw_events = tracing::field::Empty);
+        #[cfg(feature = "tracing")]
+        let _enter = span.enter();
+        #[cfg(feature = "tracing")]
+        let raw_events = self.stats.raw_events;
+
+        let result = loop {
+            match self.poll_event(cx) {
+                Poll::Ready(Some(event)) => match self.finish_event(event) {
+                    Some(event) => break Poll::Ready(Some(event)),
+                    None => continue,
+                },
+                result => break result,
+            }
+        };
+
+        // Number of inotify events processed to produce the result.
+        #[cfg(feature = "tracing")]
+        span.record("raw_events", &(self.stats.raw_events - raw_events));
+        result
+    }
+
+    /// Returns the next event read from the inotify file descriptor.
+    fn next_inotify_event(&mut self, cx: &mut Context) -> Poll<Option<io::Result<EventOwned>>> {
+        match self.read_events.as_mut() {
+            // Once the events read via read_events_into() have been translated, the buffer
+            // counts as drained.
+            Some(events) => match events.pop_front() {
+                Some(event) => Poll::Ready(Some(Ok(event))),
+                None => Poll::Pending,
+            },
+            None => self.stream.as_mut().unwrap().as_mut().poll_next(cx),
         }
     }
 
     fn poll_inotify_stream(&mut self, cx: &mut Context) -> Poll<Option<FileSystemEvent>> {
+        if self.stream.is_none() && self.read_events.is_none() {
+            // Registers the inotify file descriptor with the reactor of the current runtime.
+            let buffer_size = self.options.buffer_size.max(MIN_BUFFER_SIZE);
+            match self.inotify.event_stream(InotifyBuffer {
+                data: vec![0; buffer_size],
+            }) {
+                Ok(stream) => self.stream = Some(Box::pin(stream)),
+                Err(e) => return Poll::Ready(Some(FileSystemEvent::Error(e.into()))),
+            }
+        }
         loop {
-            match Pin::as_mut(&mut self.stream).poll_next(cx) {
+            match self.next_inotify_event(cx) {
                 Poll::Pending => {
+                    self.last_modified = None;
+                    // The buffer has been drained, so the remaining moves most likely have their
+                    // destination outside of the watched tree.
+                    if let Some(event) = self.flush_pending_move() {
+                        return Poll::Ready(Some(event));
+                    }
                     return Poll::Pending;
                 }
-                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Ready(None) => {
+                    self.stream_terminated();
+                    return Poll::Ready(None);
+                }
                 Poll::Ready(Some(Ok(event))) => {
-                    let translated = self.translate_inotify_event(event);
+                    let raw = if self.options.raw_events {
+                        Some(self.raw_event(&event))
+                    } else {
+                        None
+                    };
+                    let mut translated = self.translate_inotify_event(event);
+                    if let Some(event) = &translated {
+                        if self.options.coalesce_modifications {
+                            // Large writes cause lots of MODIFY events for the same file, we only
+                            // report the first of a sequence of such events.
+                            let modified = match event {
+                                FileSystemEvent::FileModified(path, _) => Some(path),
+                                _ => None,
+                            };
+                            if modified.is_some() && modified == self.last_modified.as_ref() {
+                                translated = None;
+                            } else {
+                                self.last_modified = modified.cloned();
+                            }
+                        }
+                    }
+                    if let Some(raw) = raw {
+                        // The translated event is returned right after the raw event.
+                        if let Some(event) = translated {
+                            self.queued_events.push_front(event);
+                        }
+                        return Poll::Ready(Some(raw));
+                    }
                     if let Some(event) = translated {
                         return Poll::Ready(Some(event));
                     } else {
                         // Some inotify events do not directly translate into our events, such
-                        // as MOVED_FROM/MOVED_TO. Simply try to read the next event.
+                        // as MOVED_FROM. Simply try to read the next event.
                     }
                 }
+                Poll::Ready(Some(Err(ref e))) if is_transient(e) => {
+                    // E.g., the read was interrupted by a signal, so we simply read again.
+                    trace!("transient inotify error: {}", e);
+                }
                 Poll::Ready(Some(Err(e))) => {
-                    return Poll::Ready(Some(FileSystemEvent::Error(e.into())))
+                    // Reading from the inotify file descriptor most likely fails again, so the
+                    // error is only reported once and the stream ends afterwards.
+                    warn!("reading inotify events failed: {}", e);
+                    self.close();
+                    return Poll::Ready(Some(FileSystemEvent::Error(e.into())));
                 }
             };
         }
     }
-}
 
-impl Stream for FileSystemWatcherInotify {
-    type Item = FileSystemEvent;
+    /// Cleans up once the inotify stream has terminated.
+    ///
+    /// The IGNORED events for watches in removed_watches will never be read, so the remaining
+    /// watches are removed and the bookkeeping is reset right away instead of waiting for them.
+    fn stream_terminated(&mut self) {
+        debug!(
+            "inotify stream terminated with {} pending watch removals",
+            self.removed_watches.len()
+        );
+        self.close();
+    }
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        // Safe, as we will not move self_.
-        let self_ = unsafe { self.get_unchecked_mut() };
+    /// Reads and drops the events while the watcher is paused with `PausePolicy::Discard`.
+    ///
+    /// The events still have to be translated, so that the watches are updated, and events which
+    /// are always passed through by `EventKindSet` are returned.
+    fn poll_discarded(&mut self, cx: &mut Context) -> Poll<Option<FileSystemEvent>> {
+        loop {
+            while let Some(event) = self.queued_events.pop_front() {
+                if EventKindSet::from_kind(event.kind()).is_none() {
+                    return Poll::Ready(Some(event));
+                }
+            }
+            match self.poll_inotify_stream(cx) {
+                Poll::Ready(Some(event)) => {
+                    if EventKindSet::from_kind(event.kind()).is_none() {
+                        return Poll::Ready(Some(event));
+                    }
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => {
+                    self.waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+
+    fn poll_event(&mut self, cx: &mut Context) -> Poll<Option<FileSystemEvent>> {
+        if self.closed {
+            return Poll::Ready(None);
+        }
+
+        // Overflow events are returned even while the watcher is paused.
+        if self.overflow_pending {
+            self.overflow_pending = false;
+            return Poll::Ready(Some(FileSystemEvent::Overflow));
+        }
+
+        match self.paused {
+            Some(PausePolicy::Buffer) => {
+                // The events stay in the inotify buffer until resume() wakes us up again.
+                self.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            Some(PausePolicy::Discard) => return self.poll_discarded(cx),
+            None => {}
+        }
+
+        // Existing contents of a directory are reported right after the directory is watched.
+        if let Some(event) = self.queued_events.pop_front() {
+            return Poll::Ready(Some(event));
+        }
 
         // Here, the order is important to prevent watch descriptor reuse. We must first drain
         // the inotify buffer before we can add any new watches. See
         // https://github.com/hannobraun/inotify/issues/73 for a description of the issue.
 
-        match self_.poll_inotify_stream(cx) {
+        match self.poll_inotify_stream(cx) {
             Poll::Pending => {
                 // Continue below and add any
             }
             x => return x,
         }
 
-        loop {
-            if !self_.new_directories.is_empty() {
-                // Install an inotify watch for a new directory and report that the directory was added.
-                let new_directory = self_.new_directories.iter().next().unwrap().clone();
-                self_.new_directories.remove(&new_directory);
-
-                // TODO: Do not follow links!
-                // TODO: Is ONLYDIR correct?
-                if Path::new(&new_directory).is_dir() {
-                    let watch = match self_.inotify.add_watch(
-                        &new_directory,
-                        WatchMask::ATTRIB
-                            | WatchMask::CREATE
-                            | WatchMask::DELETE
-                            | WatchMask::DELETE_SELF
-                            | WatchMask::MODIFY
-                            | WatchMask::MOVE
-                            | WatchMask::EXCL_UNLINK
-                            | WatchMask::ONLYDIR,
-                    ) {
-                        Ok(watch) => watch,
-                        Err(e) => return Poll::Ready(Some(FileSystemEvent::Error(e.into()))),
-                    };
+        if !self.removed_watches.is_empty() {
+            // The IGNORED events for removed watches are still outstanding, we will be woken up
+            // once they arrive.
+            return Poll::Pending;
+        }
 
-                    // Enter the directory into the list of active watches.
-                    self_
-                        .watches_by_path
-                        .insert(new_directory.clone(), watch.clone());
-                    self_
-                        .paths_by_watch
-                        .insert(watch.clone(), new_directory.clone());
-
-                    // Enter all subdirectories into the list of new directories.
-                    self_.watch_subdirectories(&new_directory);
-
-                    return Poll::Ready(Some(FileSystemEvent::DirectoryWatched(new_directory)));
-                } else {
-                    // We failed, but there might be more new directories. Just try again.
-                    continue;
+        #[cfg(feature = "delay")]
+        {
+            if let Some(timer) = self.retry_timer.as_mut() {
+                if Pin::as_mut(timer).poll(cx).is_ready() {
+                    self.retry_timer = None;
+                    let unreadable =
+                        mem::replace(&mut self.unreadable_directories, BTreeSet::new());
+                    self.new_directories.extend(unreadable);
                 }
             }
+        }
 
-            // No events, no new directories.
-            return Poll::Pending;
+        while let Some(root) = self.detached_roots.iter().next().cloned() {
+            self.detached_roots.remove(&root);
+            if let Some(event) = self.watch_parent(root) {
+                return Poll::Ready(Some(event));
+            }
+        }
+
+        // Install inotify watches for new directories and report that the directories were added.
+        // If watch_batch_size is larger than 1, multiple watches are installed and reported at
+        // once.
+        let mut batch = Vec::new();
+        while let Some(new_directory) = self.new_directories.iter().next().cloned() {
+            self.new_directories.remove(&new_directory);
+            match self.install_watch(new_directory) {
+                Some(FileSystemEvent::DirectoryWatched(path))
+                    if self.options.watch_batch_size > 1 =>
+                {
+                    batch.push(path);
+                    if batch.len() >= self.options.watch_batch_size {
+                        break;
+                    }
+                }
+                Some(event) if batch.is_empty() => return Poll::Ready(Some(event)),
+                Some(event) => {
+                    // Reported after the batch and the existing contents of its directories.
+                    self.queued_events.push_back(event);
+                    break;
+                }
+                None => {
+                    if batch.is_empty() {
+                        if let Some(event) = self.queued_events.pop_front() {
+                            return Poll::Ready(Some(event));
+                        }
+                    }
+                }
+            }
+        }
+        if !batch.is_empty() {
+            return Poll::Ready(Some(FileSystemEvent::DirectoriesWatched(batch)));
+        }
+
+        // No events, no new directories. The first time we get here, all directories which
+        // existed initially are watched.
+        if !self.scan_complete {
+            self.scan_complete = true;
+            return Poll::Ready(Some(FileSystemEvent::ScanComplete));
+        }
+        if self.options.stop_after_scan {
+            self.close();
+            return Poll::Ready(None);
+        }
+        self.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Installs the watch for a new directory (or root file) and returns the event which reports
+    /// it, or `None` if no watch was installed.
+    fn install_watch(&mut self, new_directory: OsString) -> Option<FileSystemEvent> {
+        let created = self.created_directories.remove(&new_directory);
+        if self.watches_by_path.contains_key(&new_directory) {
+            // The directory is already being watched, but rescan() requested to search for
+            // missing watches below it and to report the contents again.
+            if self.options.recursive || self.options.emit_existing {
+                self.watch_subdirectories(&new_directory, self.options.emit_existing);
+            }
+            return None;
+        }
+
+        // TODO: Is ONLYDIR correct?
+        if Path::new(&new_directory).is_dir() {
+            #[cfg(feature = "tracing")]
+            let span = tracing::debug_span!(
+                "add_watch",
+                path = %new_directory.to_string_lossy(),
+                pending = self.new_directories.len() as u64,
+                subdirectories = tracing::field::Empty
+            );
+            #[cfg(feature = "tracing")]
+            let _enter = span.enter();
+
+            let mut mask = watch_mask(&self.options);
+            // Symlinks to directories are only entered into new_directories if they shall be
+            // followed, but the directory could have been replaced by a link since. The root
+            // directories themselves may always be links.
+            if !self.options.follow_symlinks && !self.roots.contains(&new_directory) {
+                mask |= WatchMask::DONT_FOLLOW;
+            }
+            let watch = match self.inotify.add_watch(&new_directory, mask) {
+                Ok(watch) => watch,
+                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                    let error = Error::AddWatch {
+                        path: new_directory.clone(),
+                        source: e,
+                    };
+                    return self.unreadable_directory(new_directory, error);
+                }
+                Err(e) => return Some(add_watch_failed(new_directory, e)),
+            };
+
+            if let Some(existing) = self.paths_by_watch.get(&watch) {
+                // inotify returns the existing watch descriptor if the directory is already being
+                // watched, which happens if symlinks are followed and form a cycle (or point to
+                // another watched directory). The directory is already covered by the existing
+                // watch, so we must not descend into it again.
+                debug!(
+                    "{} is already watched as {}",
+                    new_directory.to_string_lossy(),
+                    existing.to_string_lossy()
+                );
+                return None;
+            }
+
+            // Enter the directory into the list of active watches.
+            self.stats.watches_added += 1;
+            self.watches_by_path
+                .insert(new_directory.clone(), watch.clone());
+            self.paths_by_watch
+                .insert(watch.clone(), new_directory.clone());
+            self.check_watch_limit();
+
+            // Enter all subdirectories into the list of new directories and report the existing
+            // contents if requested. The subdirectories of known directories have been passed to
+            // the builder instead.
+            #[cfg(feature = "tracing")]
+            let pending = self.new_directories.len();
+            let known = self.unscanned_directories.remove(&new_directory);
+            let emit_existing = self.options.emit_existing || created;
+            if !known && (self.options.recursive || emit_existing) {
+                self.watch_subdirectories(&new_directory, emit_existing);
+            }
+            #[cfg(feature = "tracing")]
+            span.record(
+                "subdirectories",
+                &(self.new_directories.len().saturating_sub(pending) as u64),
+            );
+
+            Some(FileSystemEvent::DirectoryWatched(new_directory))
+        } else if self.roots.contains(&new_directory) && Path::new(&new_directory).is_file() {
+            // Single files can be watched as well, but only if they were passed as root.
+            let mask = file_watch_mask(&self.options);
+            let watch = match self.inotify.add_watch(&new_directory, mask) {
+                Ok(watch) => watch,
+                Err(e) => return Some(add_watch_failed(new_directory, e)),
+            };
+            self.watches_by_path
+                .insert(new_directory.clone(), watch.clone());
+            self.paths_by_watch.insert(watch, new_directory.clone());
+            self.file_roots.insert(new_directory.clone());
+            self.stats.watches_added += 1;
+            self.check_watch_limit();
+            Some(FileSystemEvent::FileWatched(new_directory))
+        } else {
+            // We failed, but there might be more new directories.
+            None
+        }
+    }
+}
+
+impl Stream for FileSystemWatcherInotify {
+    type Item = FileSystemEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        // Safe, as we will not move self_.
+        let self_ = unsafe { self.get_unchecked_mut() };
+
+        if self_.read_events.is_some() {
+            // The events are read via read_events_into(), see there.
+            warn!("inotify watcher polled as a stream after read_events_into()");
+            return Poll::Ready(None);
+        }
+        self_.poll_translated(cx)
+    }
+}
+
+impl Drop for FileSystemWatcherInotify {
+    fn drop(&mut self) {
+        // Closing the inotify file descriptor (which is shared with the event stream) removes
+        // all kernel watches, but we remove them explicitly so that the bookkeeping stays
+        // consistent even if the watcher is dropped during the initial scan.
+        if !self.closed {
+            self.close();
         }
+        debug!(
+            "inotify watcher dropped, {} watches removed",
+            self.stats.watches_removed
+        );
+    }
+}
+
+/// Queries the metadata of a file for FileCreated and FileModified events if requested.
+fn file_metadata(options: &WatcherOptions, path: &OsStr) -> Option<FileMetadata> {
+    if !options.file_metadata {
+        return None;
+    }
+    // The file is not followed if it is a symlink, as the event refers to the link itself.
+    let metadata = fs::symlink_metadata(path).ok()?;
+    Some(FileMetadata {
+        inode: metadata.ino(),
+        device: metadata.dev(),
+        size: metadata.size(),
+        modified: metadata.modified().ok(),
+    })
+}
+
+/// Returns whether reading from the inotify file descriptor failed temporarily and can simply be
+/// retried.
+fn is_transient(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock => true,
+        _ => false,
+    }
+}
+
+/// Returns the event for a failed add_watch() call. The directory is not monitored, but all other
+/// watches stay intact.
+fn add_watch_failed(path: OsString, e: io::Error) -> FileSystemEvent {
+    let raw_error = e.raw_os_error();
+    if raw_error == Some(libc::ENOSPC) || raw_error == Some(libc::EMFILE) {
+        return FileSystemEvent::Error(Error::WatchLimitExceeded(path));
+    }
+    FileSystemEvent::WatchFailed(path.clone(), Error::AddWatch { path, source: e })
+}
+
+/// Returns the events for which watches are installed.
+///
+/// Creation, removal and moves are always required to keep track of the subdirectories, whereas
+/// modifications are only requested if the corresponding events shall be generated.
+fn watch_mask(options: &WatcherOptions) -> WatchMask {
+    let mask = WatchMask::CREATE
+        | WatchMask::DELETE
+        | WatchMask::DELETE_SELF
+        | WatchMask::MOVE
+        | WatchMask::MOVE_SELF
+        | WatchMask::ONLYDIR
+        | modification_mask(options);
+    if options.exclude_unlinked {
+        mask | WatchMask::EXCL_UNLINK
+    } else {
+        mask
+    }
+}
+
+/// Returns the events for which watches for single files are installed.
+fn file_watch_mask(options: &WatcherOptions) -> WatchMask {
+    WatchMask::DELETE_SELF | WatchMask::MOVE_SELF | modification_mask(options)
+}
+
+fn modification_mask(options: &WatcherOptions) -> WatchMask {
+    let mut mask = WatchMask::empty();
+    if options.events.contains(EventKindSet::FILE_MODIFIED) {
+        mask |= WatchMask::MODIFY;
+    }
+    // Attribute changes are reported as FileModified if fold_attribute_changes is set.
+    let attrib_kinds = if options.fold_attribute_changes {
+        EventKindSet::FILE_MODIFIED | EventKindSet::DIRECTORY_MODIFIED
+    } else {
+        EventKindSet::FILE_ATTRIBUTES_CHANGED | EventKindSet::DIRECTORY_MODIFIED
+    };
+    if options.events.intersects(attrib_kinds) {
+        mask |= WatchMask::ATTRIB;
+    }
+    if options.events.contains(EventKindSet::FILE_WRITE_CLOSED) {
+        mask |= WatchMask::CLOSE_WRITE;
+    }
+    // Read-only accesses are very frequent, so they are only requested if explicitly selected.
+    if options.events.contains(EventKindSet::FILE_OPENED) {
+        mask |= WatchMask::OPEN;
+    }
+    if options.events.contains(EventKindSet::FILE_ACCESSED) {
+        mask |= WatchMask::ACCESS;
+    }
+    if options.events.contains(EventKindSet::FILE_CLOSED) {
+        mask |= WatchMask::CLOSE_NOWRITE;
+    }
+    mask
+}
+
+/// Selects `path` and all paths below it from a sorted range of paths starting at `path`.
+///
+/// The maps are sorted byte-wise, so entries such as `/a/foo.bak` are sorted between `/a/foo` and
+/// `/a/foo/bar`. All entries which have `path` as a byte prefix are contiguous, though, so we stop
+/// at the first entry without that prefix and check the remaining entries component-wise.
+fn paths_below<'a>(path: &OsStr, range: impl Iterator<Item = &'a OsString>) -> Vec<OsString> {
+    range
+        .take_while(|p| p.as_bytes().starts_with(path.as_bytes()))
+        .filter(|p| Path::new(p).starts_with(path))
+        .cloned()
+        .collect()
+}
+
+/// Returns the value of the first entry of a sorted range which is `path` or below it, see
+/// `paths_below()`.
+fn first_below<'a, V>(
+    path: &OsStr,
+    range: impl Iterator<Item = (&'a OsString, &'a V)>,
+) -> Option<&'a V> {
+    range
+        .take_while(|(p, _)| p.as_bytes().starts_with(path.as_bytes()))
+        .find(|(p, _)| Path::new(p).starts_with(path))
+        .map(|(_, value)| value)
+}
+
+/// Replaces the prefix `from` of `path` with `to`.
+fn rename_path(path: &OsStr, from: &OsStr, to: &OsStr) -> OsString {
+    let relative = Path::new(path).strip_prefix(from).unwrap();
+    if relative.as_os_str().is_empty() {
+        // Path::join() would append a trailing slash.
+        to.to_owned()
+    } else {
+        Path::new(to).join(relative).into_os_string()
     }
 }
 
 struct InotifyBuffer {
-    data: [u8; 1024],
+    data: Vec<u8>,
 }
 
 impl AsMut<[u8]> for InotifyBuffer {
@@ -327,3 +1659,1187 @@ impl AsRef<[u8]> for InotifyBuffer {
         &self.data
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::io::{self, Write};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixListener;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use inotify::EventMask;
+    use tokio::time;
+
+    use super::{is_transient, FileSystemWatcherInotify};
+    use crate::builder::WatcherOptions;
+    use crate::{
+        Error, EventKind, EventKindSet, FileSystemEvent, FileSystemWatcher,
+        FileSystemWatcherBuilder, PausePolicy, UnreadableDirectoryPolicy,
+    };
+
+    /// Returns all events until no event has been received for a short time.
+    async fn collect_events(watcher: &mut FileSystemWatcher) -> Vec<FileSystemEvent> {
+        let mut events = Vec::new();
+        while let Ok(Some(event)) = time::timeout(Duration::from_millis(200), watcher.next()).await
+        {
+            events.push(event);
+        }
+        events
+    }
+
+    #[tokio::test]
+    async fn unwatch_only_removes_the_subtree() {
+        let dir = tempfile::tempdir().unwrap();
+        // "foo.bak" and "foo-x" are sorted between "foo" and "foo/bar" when comparing bytes.
+        for subdir in &["foo", "foo/bar", "foo.bak", "foo-x", "foobar"] {
+            fs::create_dir(dir.path().join(subdir)).unwrap();
+        }
+        let mut watcher = FileSystemWatcher::new(dir.path().as_os_str()).unwrap();
+        collect_events(&mut watcher).await;
+        assert_eq!(watcher.watched_paths().len(), 6);
+
+        watcher.unwatch(dir.path().join("foo").as_os_str());
+        let mut expected = vec![dir.path().as_os_str().to_owned()];
+        for subdir in &["foo-x", "foo.bak", "foobar"] {
+            expected.push(dir.path().join(subdir).into_os_string());
+        }
+        assert_eq!(watcher.watched_paths(), expected);
+    }
+
+    #[tokio::test]
+    async fn pending_watches_are_counted() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        let mut watcher = FileSystemWatcher::new(dir.path().as_os_str()).unwrap();
+        assert_eq!(watcher.pending_watch_count(), 1);
+        // Watching the root directory discovers its subdirectory.
+        watcher.next().await;
+        assert_eq!(watcher.pending_watch_count(), 1);
+        collect_events(&mut watcher).await;
+        assert_eq!(watcher.pending_watch_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn scan_complete_is_reported_once() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("a")).unwrap();
+        let mut watcher = FileSystemWatcher::new(dir.path().as_os_str()).unwrap();
+        let events = collect_events(&mut watcher).await;
+        assert_eq!(format!("{:?}", events.last()), "Some(ScanComplete)");
+        assert_eq!(watcher.pending_watch_count(), 0);
+
+        fs::create_dir(dir.path().join("b")).unwrap();
+        let events = collect_events(&mut watcher).await;
+        assert!(!format!("{:?}", events).contains("ScanComplete"));
+    }
+
+    #[tokio::test]
+    async fn watches_are_installed_in_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        for subdir in &["a", "b", "c", "d", "e"] {
+            fs::create_dir(dir.path().join(subdir)).unwrap();
+        }
+        let mut watcher = FileSystemWatcherBuilder::new(dir.path().as_os_str())
+            .watch_batch_size(4)
+            .build()
+            .unwrap();
+        let mut events = collect_events(&mut watcher).await;
+        assert_eq!(format!("{:?}", events.pop()), "Some(ScanComplete)");
+        let sizes = events
+            .iter()
+            .map(|event| match event {
+                FileSystemEvent::DirectoriesWatched(paths) => paths.len(),
+                event => panic!("unexpected event: {:?}", event),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, vec![4, 2]);
+        assert_eq!(watcher.watched_paths().len(), 6);
+    }
+
+    #[tokio::test]
+    async fn directory_metadata_changes_are_reported_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let sub = dir.path().join("sub");
+        fs::create_dir(&sub).unwrap();
+        let mut watcher = FileSystemWatcher::new(dir.path().as_os_str()).unwrap();
+        collect_events(&mut watcher).await;
+
+        for path in &[dir.path(), sub.as_path()] {
+            fs::set_permissions(path, fs::Permissions::from_mode(0o750)).unwrap();
+        }
+        assert_eq!(
+            format!("{:?}", collect_events(&mut watcher).await),
+            format!(
+                "{:?}",
+                vec![
+                    FileSystemEvent::DirectoryModified(dir.path().into()),
+                    FileSystemEvent::DirectoryModified(sub.into_os_string()),
+                ]
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn excluded_paths_are_not_watched() {
+        let dir = tempfile::tempdir().unwrap();
+        for subdir in &["a", "b", "c"] {
+            fs::create_dir(dir.path().join(subdir)).unwrap();
+        }
+        let mut watcher = FileSystemWatcherBuilder::new(dir.path().as_os_str())
+            .exclude_paths(vec![dir.path().join("b").into()])
+            .build()
+            .unwrap();
+        collect_events(&mut watcher).await;
+        let mut expected = vec![dir.path().as_os_str().to_owned()];
+        for subdir in &["a", "c"] {
+            expected.push(dir.path().join(subdir).into_os_string());
+        }
+        assert_eq!(watcher.watched_paths(), expected);
+
+        for subdir in &["a", "b", "c"] {
+            fs::write(dir.path().join(subdir).join("file"), "a").unwrap();
+        }
+        fs::create_dir(dir.path().join("b/sub")).unwrap();
+        let events = format!("{:?}", collect_events(&mut watcher).await);
+        assert!(events.contains("/a/file"));
+        assert!(events.contains("/c/file"));
+        assert!(!events.contains("/b/"), "unexpected events: {}", events);
+    }
+
+    #[tokio::test]
+    async fn file_metadata_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher = FileSystemWatcherBuilder::new(dir.path().as_os_str())
+            .file_metadata(true)
+            .build()
+            .unwrap();
+        collect_events(&mut watcher).await;
+
+        let file = dir.path().join("a");
+        fs::write(&file, "abc").unwrap();
+        fs::hard_link(&file, dir.path().join("b")).unwrap();
+        let metadata = collect_events(&mut watcher)
+            .await
+            .into_iter()
+            .filter_map(|event| match event {
+                FileSystemEvent::FileCreated(_, metadata) => Some(metadata.unwrap()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(metadata.len(), 2);
+        // Both names refer to the same inode.
+        assert_eq!(metadata[0].inode, metadata[1].inode);
+        assert_eq!(metadata[0].device, metadata[1].device);
+        assert_eq!(metadata[1].size, 3);
+    }
+
+    #[tokio::test]
+    async fn unreadable_directories_are_retried() {
+        if unsafe { libc::geteuid() } == 0 {
+            // Permissions are not enforced for root.
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let locked = dir.path().join("locked");
+        fs::create_dir(&locked).unwrap();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+
+        let mut watcher = FileSystemWatcherBuilder::new(dir.path().as_os_str())
+            .unreadable_directories(UnreadableDirectoryPolicy::Skip)
+            .build()
+            .unwrap();
+        let events = collect_events(&mut watcher).await;
+        assert!(!format!("{:?}", events).contains("WatchFailed"));
+
+        let mut watcher = FileSystemWatcherBuilder::new(dir.path().as_os_str())
+            .unreadable_directories(UnreadableDirectoryPolicy::Retry(Duration::from_millis(500)))
+            .build()
+            .unwrap();
+        let events = collect_events(&mut watcher).await;
+        assert_eq!(
+            events
+                .iter()
+                .filter(|event| event.kind() == EventKind::WatchFailed)
+                .count(),
+            1
+        );
+
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+        // The permission change itself is reported as DirectoryModified before.
+        let watched = time::timeout(
+            Duration::from_secs(2),
+            watcher.wait_for(|event| event.kind() == EventKind::DirectoryWatched),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            watched.and_then(|event| event.path().map(Path::to_owned)),
+            Some(locked)
+        );
+    }
+
+    #[tokio::test]
+    async fn exceeding_the_soft_watch_limit_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        for subdir in &["a", "b", "c"] {
+            fs::create_dir(dir.path().join(subdir)).unwrap();
+        }
+        let mut watcher = FileSystemWatcherBuilder::new(dir.path().as_os_str())
+            .soft_watch_limit(2)
+            .build()
+            .unwrap();
+        let events = collect_events(&mut watcher).await;
+        assert_eq!(watcher.watch_count(), 4);
+        let warnings = events
+            .iter()
+            .filter_map(|event| match event {
+                FileSystemEvent::WatchLimitWarning(count) => Some(*count),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(warnings, vec![3]);
+    }
+
+    #[tokio::test]
+    async fn known_directories_are_not_scanned() {
+        let dir = tempfile::tempdir().unwrap();
+        for subdir in &["a/b", "c"] {
+            fs::create_dir_all(dir.path().join(subdir)).unwrap();
+        }
+        let a = dir.path().join("a");
+        let mut watcher = FileSystemWatcherBuilder::new(dir.path().as_os_str())
+            .known_directories(vec![dir.path().into(), a.clone().into()])
+            .build()
+            .unwrap();
+        collect_events(&mut watcher).await;
+        assert_eq!(
+            watcher.watched_paths(),
+            vec![
+                dir.path().as_os_str().to_owned(),
+                a.clone().into_os_string()
+            ]
+        );
+
+        // New directories are still scanned.
+        fs::create_dir_all(a.join("new/sub")).unwrap();
+        collect_events(&mut watcher).await;
+        assert_eq!(watcher.watched_paths().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn writes_to_unlinked_files_can_be_reported() {
+        for &exclude_unlinked in &[true, false] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("log");
+            let mut file = fs::File::create(&path).unwrap();
+            let mut watcher = FileSystemWatcherBuilder::new(dir.path().as_os_str())
+                .exclude_unlinked(exclude_unlinked)
+                .build()
+                .unwrap();
+            collect_events(&mut watcher).await;
+
+            fs::remove_file(&path).unwrap();
+            file.write_all(b"a").unwrap();
+            let mut expected = vec![FileSystemEvent::FileRemoved(path.clone().into())];
+            if !exclude_unlinked {
+                expected.push(FileSystemEvent::FileModified(path.into(), None));
+            }
+            assert_eq!(
+                format!("{:?}", collect_events(&mut watcher).await),
+                format!("{:?}", expected)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn unchanged_modifications_can_be_skipped() {
+        for &skip in &[false, true] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("file");
+            fs::write(&path, "abc").unwrap();
+            let mut watcher = FileSystemWatcherBuilder::new(dir.path().as_os_str())
+                .skip_unchanged_modifications(skip)
+                .build()
+                .unwrap();
+            collect_events(&mut watcher).await;
+            // The first modification is always reported, as the previous state is unknown.
+            fs::write(&path, "abcd").unwrap();
+            collect_events(&mut watcher).await;
+
+            // Rewrite the file with the same contents and restore the modification time.
+            let metadata = fs::metadata(&path).unwrap();
+            let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+            file.write_all(b"abcd").unwrap();
+            let time = libc::timespec {
+                tv_sec: metadata.mtime(),
+                tv_nsec: metadata.mtime_nsec(),
+            };
+            let times = [time, time];
+            assert_eq!(
+                unsafe { libc::futimens(file.as_raw_fd(), times.as_ptr()) },
+                0
+            );
+
+            let mut expected = Vec::new();
+            if !skip {
+                expected.push(FileSystemEvent::FileModified(path.clone().into(), None));
+            }
+            expected.push(FileSystemEvent::FileAttributesChanged(path.into()));
+            assert_eq!(
+                format!("{:?}", collect_events(&mut watcher).await),
+                format!("{:?}", expected)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn pending_removals_are_cleaned_up_when_the_stream_ends() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("a")).unwrap();
+        let mut watcher =
+            FileSystemWatcherInotify::new(&[dir.path().into()], WatcherOptions::default()).unwrap();
+        while let Ok(Some(_)) = time::timeout(Duration::from_millis(200), watcher.next()).await {}
+        assert_eq!(watcher.watch_count(), 2);
+
+        // The IGNORED event for the removed watch has not been read yet.
+        watcher.unwatch(dir.path().join("a").as_os_str());
+        assert!(!watcher.removed_watches.is_empty());
+
+        watcher.stream_terminated();
+        assert!(watcher.removed_watches.is_empty());
+        assert_eq!(watcher.watch_count(), 0);
+        assert!(watcher.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn non_utf8_paths_can_be_reported_separately() {
+        for &strict_paths in &[false, true] {
+            let dir = tempfile::tempdir().unwrap();
+            let mut watcher = FileSystemWatcherBuilder::new(dir.path().as_os_str())
+                .strict_paths(strict_paths)
+                .build()
+                .unwrap();
+            collect_events(&mut watcher).await;
+
+            let path = dir.path().join(OsStr::from_bytes(b"invalid-\xff"));
+            fs::write(&path, "").unwrap();
+            let expected = if strict_paths {
+                FileSystemEvent::NonUtf8Path(path.into(), EventKind::FileCreated)
+            } else {
+                FileSystemEvent::FileCreated(path.into(), None)
+            };
+            assert_eq!(
+                format!("{:?}", collect_events(&mut watcher).await),
+                format!("{:?}", vec![expected])
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn contents_of_new_directories_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher = FileSystemWatcherBuilder::new(dir.path().as_os_str())
+            .scan_on_create(true)
+            .build()
+            .unwrap();
+        collect_events(&mut watcher).await;
+
+        // The files are created before the watcher has a chance to watch the new directories.
+        let mut expected = BTreeSet::new();
+        for i in 0..20 {
+            let subdir = dir.path().join(format!("dir{}", i));
+            fs::create_dir(&subdir).unwrap();
+            fs::create_dir(subdir.join("nested")).unwrap();
+            for j in 0..10 {
+                let file = subdir.join(format!("file{}", j));
+                fs::write(&file, "").unwrap();
+                expected.insert(file);
+            }
+            let file = subdir.join("nested/file");
+            fs::write(&file, "").unwrap();
+            expected.insert(file);
+        }
+        let created = collect_events(&mut watcher)
+            .await
+            .into_iter()
+            .filter_map(|event| match event {
+                FileSystemEvent::FileCreated(path, _) => Some(PathBuf::from(path)),
+                _ => None,
+            })
+            .collect::<BTreeSet<_>>();
+        assert_eq!(created, expected);
+    }
+
+    #[tokio::test]
+    async fn renames_are_reported_as_moves() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("a.txt"), "").unwrap();
+        let mut watcher = FileSystemWatcher::new(dir.path().as_os_str()).unwrap();
+        collect_events(&mut watcher).await;
+
+        fs::rename(dir.path().join("a.txt"), dir.path().join("b.txt")).unwrap();
+        fs::rename(dir.path().join("b.txt"), dir.path().join("sub/b.txt")).unwrap();
+        let events = collect_events(&mut watcher).await;
+        assert_eq!(
+            format!("{:?}", events),
+            format!(
+                "{:?}",
+                vec![
+                    FileSystemEvent::FileMoved(
+                        dir.path().join("a.txt").into(),
+                        dir.path().join("b.txt").into()
+                    ),
+                    FileSystemEvent::FileMoved(
+                        dir.path().join("b.txt").into(),
+                        dir.path().join("sub/b.txt").into()
+                    ),
+                ]
+            )
+        );
+        assert!(events[0].same_parent());
+        assert!(!events[1].same_parent());
+    }
+
+    #[tokio::test]
+    async fn queued_events_are_limited() {
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..10 {
+            fs::write(dir.path().join(format!("file{}", i)), "").unwrap();
+        }
+        let mut watcher = FileSystemWatcherBuilder::new(dir.path().as_os_str())
+            .emit_existing(true)
+            .max_queued_events(3)
+            .build()
+            .unwrap();
+        let events = collect_events(&mut watcher).await;
+        let created = events
+            .iter()
+            .filter(|event| event.kind() == EventKind::FileCreated)
+            .count();
+        assert_eq!(created, 3);
+        assert_eq!(
+            format!("{:?}", &events[..2]),
+            format!(
+                "{:?}",
+                vec![
+                    FileSystemEvent::DirectoryWatched(dir.path().into()),
+                    FileSystemEvent::Overflow,
+                ]
+            )
+        );
+        assert_eq!(watcher.stats().dropped_events, 7);
+        assert_eq!(watcher.stats().count(EventKind::Overflow), 1);
+    }
+
+    #[tokio::test]
+    async fn stats_are_counted() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("a")).unwrap();
+        let mut watcher = FileSystemWatcher::new(dir.path().as_os_str()).unwrap();
+        collect_events(&mut watcher).await;
+        fs::write(dir.path().join("file"), "a").unwrap();
+        fs::remove_dir(dir.path().join("a")).unwrap();
+        collect_events(&mut watcher).await;
+
+        let stats = watcher.stats();
+        assert_eq!(stats.watches_added, 2);
+        assert_eq!(stats.watches_removed, 1);
+        assert_eq!(stats.count(EventKind::DirectoryWatched), 2);
+        assert_eq!(stats.count(EventKind::FileCreated), 1);
+        assert_eq!(stats.count(EventKind::DirectoryRemoved), 1);
+        assert_eq!(stats.count(EventKind::Overflow), 0);
+        assert!(stats.raw_events >= 3);
+    }
+
+    #[tokio::test]
+    async fn only_selected_events_are_generated() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), "a").unwrap();
+        let mut watcher = FileSystemWatcherBuilder::new(dir.path().as_os_str())
+            .events(EventKindSet::FILE_CREATED | EventKindSet::FILE_REMOVED)
+            .build()
+            .unwrap();
+        collect_events(&mut watcher).await;
+
+        fs::write(dir.path().join("a"), "b").unwrap();
+        fs::write(dir.path().join("b"), "b").unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::remove_file(dir.path().join("a")).unwrap();
+        let events = collect_events(&mut watcher).await;
+        assert_eq!(
+            format!("{:?}", events),
+            format!(
+                "{:?}",
+                vec![
+                    FileSystemEvent::FileCreated(dir.path().join("b").into(), None),
+                    FileSystemEvent::FileRemoved(dir.path().join("a").into()),
+                ]
+            )
+        );
+        // Subdirectories are still monitored.
+        assert_eq!(watcher.watched_paths().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn closing_written_files_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher = FileSystemWatcherBuilder::new(dir.path().as_os_str())
+            .events(EventKindSet::FILE_CREATED | EventKindSet::FILE_WRITE_CLOSED)
+            .build()
+            .unwrap();
+        collect_events(&mut watcher).await;
+
+        let file = dir.path().join("a");
+        fs::write(&file, "a").unwrap();
+        let events = collect_events(&mut watcher).await;
+        assert_eq!(
+            format!("{:?}", events),
+            format!(
+                "{:?}",
+                vec![
+                    FileSystemEvent::FileCreated(file.clone().into(), None),
+                    FileSystemEvent::FileWriteClosed(file.into()),
+                ]
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn read_accesses_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a");
+        fs::write(&file, "a").unwrap();
+        let mut watcher = FileSystemWatcherBuilder::new(dir.path().as_os_str())
+            .events(
+                EventKindSet::FILE_OPENED | EventKindSet::FILE_ACCESSED | EventKindSet::FILE_CLOSED,
+            )
+            .build()
+            .unwrap();
+        collect_events(&mut watcher).await;
+
+        // Listing the directory does not generate any events.
+        fs::read_dir(dir.path()).unwrap().for_each(drop);
+        fs::read_to_string(&file).unwrap();
+        let events = collect_events(&mut watcher).await;
+        assert_eq!(
+            format!("{:?}", events),
+            format!(
+                "{:?}",
+                vec![
+                    FileSystemEvent::FileOpened(file.clone().into()),
+                    FileSystemEvent::FileAccessed(file.clone().into()),
+                    FileSystemEvent::FileClosed(file.into()),
+                ]
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn paused_watchers_buffer_or_discard_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher = FileSystemWatcher::new(dir.path().as_os_str()).unwrap();
+        collect_events(&mut watcher).await;
+
+        watcher.pause(PausePolicy::Buffer);
+        fs::write(dir.path().join("a"), "").unwrap();
+        assert!(collect_events(&mut watcher).await.is_empty());
+        watcher.resume();
+        let events = collect_events(&mut watcher).await;
+        assert_eq!(
+            format!("{:?}", events),
+            format!(
+                "{:?}",
+                vec![FileSystemEvent::FileCreated(
+                    dir.path().join("a").into(),
+                    None
+                )]
+            )
+        );
+
+        watcher.pause(PausePolicy::Discard);
+        fs::write(dir.path().join("b"), "").unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        assert!(collect_events(&mut watcher).await.is_empty());
+        watcher.resume();
+        // Only the watch for the new directory is installed after resuming.
+        let events = collect_events(&mut watcher).await;
+        assert_eq!(
+            format!("{:?}", events),
+            format!(
+                "{:?}",
+                vec![FileSystemEvent::DirectoryWatched(
+                    dir.path().join("sub").into()
+                )]
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn shutdown_returns_buffered_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher = FileSystemWatcher::new(dir.path().as_os_str()).unwrap();
+        collect_events(&mut watcher).await;
+
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        let events = watcher.shutdown().await;
+        assert_eq!(
+            format!("{:?}", events),
+            format!(
+                "{:?}",
+                vec![FileSystemEvent::DirectoryCreated(
+                    dir.path().join("sub").into()
+                )]
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn shutdown_reports_non_utf8_paths_separately() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher = FileSystemWatcherBuilder::new(dir.path().as_os_str())
+            .strict_paths(true)
+            .build()
+            .unwrap();
+        collect_events(&mut watcher).await;
+
+        let path = dir.path().join(OsStr::from_bytes(b"invalid-\xff"));
+        fs::write(&path, "").unwrap();
+        let events = watcher.shutdown().await;
+        assert_eq!(
+            format!("{:?}", events),
+            format!(
+                "{:?}",
+                vec![FileSystemEvent::NonUtf8Path(
+                    path.into(),
+                    EventKind::FileCreated
+                )]
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn shutdown_during_initial_scan() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        let mut watcher = FileSystemWatcher::new(dir.path().as_os_str()).unwrap();
+        watcher.next().await;
+        assert!(watcher.shutdown().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn watched_directories_are_not_reported_twice() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        let mut watcher = FileSystemWatcher::new(dir.path().as_os_str()).unwrap();
+        collect_events(&mut watcher).await;
+
+        let sub = dir.path().join("sub");
+        watcher.watch(sub.as_os_str());
+        watcher.watch(sub.as_os_str());
+        watcher.watch(dir.path().as_os_str());
+        assert!(collect_events(&mut watcher).await.is_empty());
+        assert_eq!(watcher.watched_paths().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn raw_events_precede_translated_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher = FileSystemWatcherBuilder::new(dir.path().as_os_str())
+            .raw_events(true)
+            .build()
+            .unwrap();
+        collect_events(&mut watcher).await;
+
+        let sub = dir.path().join("sub");
+        fs::create_dir(&sub).unwrap();
+        let events = collect_events(&mut watcher).await;
+        assert_eq!(
+            format!("{:?}", &events[..2]),
+            format!(
+                "{:?}",
+                vec![
+                    FileSystemEvent::Raw {
+                        path: sub.clone().into(),
+                        mask: (EventMask::CREATE | EventMask::ISDIR).bits(),
+                        cookie: 0,
+                    },
+                    FileSystemEvent::DirectoryCreated(sub.into()),
+                ]
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn subdirectories_beyond_max_depth_are_not_watched() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a/b/c/d")).unwrap();
+        let mut watcher = FileSystemWatcherBuilder::new(dir.path().as_os_str())
+            .max_depth(2)
+            .build()
+            .unwrap();
+        collect_events(&mut watcher).await;
+        let mut expected = vec![dir.path().as_os_str().to_owned()];
+        for subdir in &["a", "a/b"] {
+            expected.push(dir.path().join(subdir).into_os_string());
+        }
+        assert_eq!(watcher.watched_paths(), expected);
+
+        fs::write(dir.path().join("a/b/c/d/file"), "a").unwrap();
+        fs::create_dir(dir.path().join("a/b/new")).unwrap();
+        fs::write(dir.path().join("a/b/file"), "a").unwrap();
+        let events = collect_events(&mut watcher).await;
+        assert_eq!(
+            format!("{:?}", &events[..2]),
+            format!(
+                "{:?}",
+                vec![
+                    FileSystemEvent::DirectoryCreated(dir.path().join("a/b/new").into()),
+                    FileSystemEvent::FileCreated(dir.path().join("a/b/file").into(), None),
+                ]
+            )
+        );
+        assert_eq!(watcher.watched_paths(), expected);
+    }
+
+    #[test]
+    fn transient_errors_are_recognized() {
+        assert!(is_transient(&io::Error::from_raw_os_error(libc::EINTR)));
+        assert!(is_transient(&io::Error::from_raw_os_error(libc::EAGAIN)));
+        assert!(!is_transient(&io::Error::from_raw_os_error(libc::EBADF)));
+    }
+
+    #[test]
+    fn missing_roots_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+        match FileSystemWatcher::new(missing.as_os_str()) {
+            Err(Error::NotFound(path)) => assert_eq!(path, missing),
+            _ => panic!("missing root accepted"),
+        }
+    }
+
+    #[test]
+    fn events_can_be_read_without_tokio() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher = FileSystemWatcher::new(dir.path().as_os_str()).unwrap();
+        let mut buffer = vec![0; 4096];
+        let events = watcher.read_events_into(&mut buffer).unwrap();
+        assert_eq!(
+            format!("{:?}", events),
+            format!(
+                "{:?}",
+                vec![
+                    FileSystemEvent::DirectoryWatched(dir.path().into()),
+                    FileSystemEvent::ScanComplete
+                ]
+            )
+        );
+
+        let file = dir.path().join("a");
+        fs::write(&file, "").unwrap();
+        let mut pollfd = libc::pollfd {
+            fd: watcher.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        assert_eq!(unsafe { libc::poll(&mut pollfd, 1, 1000) }, 1);
+        let events = watcher.read_events_into(&mut buffer).unwrap();
+        assert_eq!(
+            format!("{:?}", events),
+            format!(
+                "{:?}",
+                vec![FileSystemEvent::FileCreated(file.into(), None)]
+            )
+        );
+        assert!(watcher.read_events_into(&mut buffer).unwrap().is_empty());
+    }
+
+    #[test]
+    fn all_available_events_are_read_at_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher = FileSystemWatcher::new(dir.path().as_os_str()).unwrap();
+        // Only holds a single event with a maximum-length file name.
+        let mut buffer = vec![0; 16 + 256];
+        watcher.read_events_into(&mut buffer).unwrap();
+
+        for i in 0..20 {
+            fs::write(dir.path().join(format!("file{}", i)), "").unwrap();
+        }
+        let events = watcher.read_events_into(&mut buffer).unwrap();
+        let created = events
+            .iter()
+            .filter(|event| event.kind() == EventKind::FileCreated)
+            .count();
+        assert_eq!(created, 20);
+    }
+
+    #[tokio::test]
+    async fn stream_and_read_events_into_are_exclusive() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut buffer = vec![0; 4096];
+        let mut watcher = FileSystemWatcher::new(dir.path().as_os_str()).unwrap();
+        collect_events(&mut watcher).await;
+        assert!(watcher.read_events_into(&mut buffer).is_err());
+
+        let mut watcher = FileSystemWatcher::new(dir.path().as_os_str()).unwrap();
+        watcher.read_events_into(&mut buffer).unwrap();
+        fs::write(dir.path().join("a"), "").unwrap();
+        assert!(watcher.next().await.is_none());
+    }
+
+    #[test]
+    fn special_files_are_rejected_as_roots() {
+        let dir = tempfile::tempdir().unwrap();
+        // Regular files are accepted (see single_files_can_be_watched), sockets are not.
+        let socket = dir.path().join("socket");
+        let _listener = UnixListener::bind(&socket).unwrap();
+        match FileSystemWatcher::new(socket.as_os_str()) {
+            Err(Error::NotADirectory(path)) => assert_eq!(path, socket),
+            _ => panic!("socket accepted as root"),
+        }
+    }
+
+    #[tokio::test]
+    async fn symlink_cycles_are_not_followed() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        std::os::unix::fs::symlink(dir.path(), dir.path().join("sub/loop")).unwrap();
+        for follow_symlinks in &[false, true] {
+            let mut watcher = FileSystemWatcherBuilder::new(dir.path().as_os_str())
+                .follow_symlinks(*follow_symlinks)
+                .build()
+                .unwrap();
+            collect_events(&mut watcher).await;
+            assert_eq!(
+                watcher.watched_paths(),
+                vec![
+                    dir.path().as_os_str().to_owned(),
+                    dir.path().join("sub").into_os_string()
+                ]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn removed_symlink_targets_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let target = outside.path().join("target");
+        fs::create_dir(&target).unwrap();
+        std::os::unix::fs::symlink(&target, dir.path().join("link")).unwrap();
+        let mut watcher = FileSystemWatcherBuilder::new(dir.path().as_os_str())
+            .follow_symlinks(true)
+            .build()
+            .unwrap();
+        collect_events(&mut watcher).await;
+        assert_eq!(watcher.watched_paths().len(), 2);
+
+        // The parent directory of the target is not watched, so only the IGNORED event for the
+        // watch of the link tells us that the target is gone.
+        fs::remove_dir(&target).unwrap();
+        assert_eq!(
+            format!("{:?}", collect_events(&mut watcher).await),
+            format!(
+                "{:?}",
+                vec![FileSystemEvent::DirectoryRemoved(
+                    dir.path().join("link").into()
+                )]
+            )
+        );
+        assert_eq!(
+            watcher.watched_paths(),
+            vec![dir.path().as_os_str().to_owned()]
+        );
+    }
+
+    #[tokio::test]
+    async fn directory_attribute_changes_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        let mut watcher = FileSystemWatcher::new(dir.path().as_os_str()).unwrap();
+        collect_events(&mut watcher).await;
+
+        let sub = dir.path().join("sub");
+        let mut permissions = fs::metadata(&sub).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&sub, permissions).unwrap();
+        let events = collect_events(&mut watcher).await;
+        assert_eq!(
+            format!("{:?}", events),
+            format!("{:?}", vec![FileSystemEvent::DirectoryModified(sub.into())])
+        );
+    }
+
+    #[tokio::test]
+    async fn resilient_root_is_watched_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir(&root).unwrap();
+        let mut watcher = FileSystemWatcherBuilder::new(root.as_os_str())
+            .resilient_root(true)
+            .build()
+            .unwrap();
+        collect_events(&mut watcher).await;
+
+        fs::remove_dir(&root).unwrap();
+        collect_events(&mut watcher).await;
+        fs::create_dir(&root).unwrap();
+        let events = collect_events(&mut watcher).await;
+        assert_eq!(
+            format!("{:?}", events),
+            format!(
+                "{:?}",
+                vec![FileSystemEvent::DirectoryWatched(root.into_os_string())]
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn root_with_trailing_slash() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut root = dir.path().as_os_str().to_owned();
+        root.push("/");
+        let mut watcher = FileSystemWatcher::new(&root).unwrap();
+        collect_events(&mut watcher).await;
+
+        fs::write(dir.path().join("a"), "a").unwrap();
+        let events = collect_events(&mut watcher).await;
+        let expected = FileSystemEvent::FileCreated(dir.path().join("a").into_os_string(), None);
+        assert_eq!(format!("{:?}", events[0]), format!("{:?}", expected));
+    }
+
+    #[tokio::test]
+    async fn rescan_reports_existing_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), "a").unwrap();
+        let mut watcher = FileSystemWatcherBuilder::new(dir.path().as_os_str())
+            .emit_existing(true)
+            .build()
+            .unwrap();
+        collect_events(&mut watcher).await;
+
+        watcher.rescan();
+        let events = collect_events(&mut watcher).await;
+        assert_eq!(
+            format!("{:?}", events),
+            format!(
+                "{:?}",
+                vec![FileSystemEvent::FileCreated(
+                    dir.path().join("a").into_os_string(),
+                    None
+                )]
+            )
+        );
+        assert_eq!(watcher.watched_paths().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn single_files_can_be_watched() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("config");
+        fs::write(&file, "a").unwrap();
+        let mut watcher = FileSystemWatcher::new(file.as_os_str()).unwrap();
+        let events = collect_events(&mut watcher).await;
+        assert_eq!(
+            format!("{:?}", events),
+            format!(
+                "{:?}",
+                vec![
+                    FileSystemEvent::FileWatched(file.clone().into()),
+                    FileSystemEvent::ScanComplete
+                ]
+            )
+        );
+
+        fs::write(&file, "b").unwrap();
+        fs::write(dir.path().join("other"), "c").unwrap();
+        let events = collect_events(&mut watcher).await;
+        assert!(!events.is_empty());
+        assert!(events.iter().all(|event| match event {
+            FileSystemEvent::FileModified(path, _) => *path == file,
+            _ => false,
+        }));
+
+        fs::remove_file(&file).unwrap();
+        let events = collect_events(&mut watcher).await;
+        let events = format!("{:?}", events);
+        assert!(events.ends_with(&format!(
+            "{:?}, Stopped(DirectoryRemoved)]",
+            FileSystemEvent::FileRemoved(file.into())
+        )));
+    }
+
+    #[tokio::test]
+    async fn moving_the_root_stops_the_watcher() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir(&root).unwrap();
+        let mut watcher = FileSystemWatcher::new(root.as_os_str()).unwrap();
+        collect_events(&mut watcher).await;
+
+        fs::rename(&root, dir.path().join("moved")).unwrap();
+        let events = collect_events(&mut watcher).await;
+        assert_eq!(format!("{:?}", events), "[Stopped(DirectoryMoved)]");
+        assert!(watcher.watched_paths().is_empty());
+    }
+
+    #[tokio::test]
+    async fn directory_moves_are_combined() {
+        let dir = tempfile::tempdir().unwrap();
+        for subdir in &["a", "a/sub", "b"] {
+            fs::create_dir(dir.path().join(subdir)).unwrap();
+        }
+        let mut watcher = FileSystemWatcher::new(dir.path().as_os_str()).unwrap();
+        collect_events(&mut watcher).await;
+
+        // Rename within the same parent directory.
+        fs::rename(dir.path().join("a/sub"), dir.path().join("a/renamed")).unwrap();
+        let events = collect_events(&mut watcher).await;
+        assert_eq!(
+            format!("{:?}", events),
+            format!(
+                "{:?}",
+                vec![
+                    FileSystemEvent::DirectoryMoved(
+                        dir.path().join("a/sub").into(),
+                        dir.path().join("a/renamed").into()
+                    ),
+                    FileSystemEvent::DirectoryWatched(dir.path().join("a/renamed").into()),
+                ]
+            )
+        );
+
+        // Move between two watched subdirectories.
+        fs::rename(dir.path().join("a/renamed"), dir.path().join("b/sub")).unwrap();
+        let events = collect_events(&mut watcher).await;
+        assert_eq!(
+            format!("{:?}", events),
+            format!(
+                "{:?}",
+                vec![
+                    FileSystemEvent::DirectoryMoved(
+                        dir.path().join("a/renamed").into(),
+                        dir.path().join("b/sub").into()
+                    ),
+                    FileSystemEvent::DirectoryWatched(dir.path().join("b/sub").into()),
+                ]
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn moved_paths_are_rewritten() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a/b/c")).unwrap();
+        let mut watcher = FileSystemWatcherBuilder::new(dir.path().as_os_str())
+            .rewrite_moved_paths(true)
+            .build()
+            .unwrap();
+        collect_events(&mut watcher).await;
+
+        fs::rename(dir.path().join("a"), dir.path().join("x")).unwrap();
+        let events = collect_events(&mut watcher).await;
+        assert_eq!(
+            format!("{:?}", events),
+            format!(
+                "{:?}",
+                vec![FileSystemEvent::DirectoryMoved(
+                    dir.path().join("a").into(),
+                    dir.path().join("x").into()
+                )]
+            )
+        );
+        let mut expected = vec![dir.path().as_os_str().to_owned()];
+        for subdir in &["x", "x/b", "x/b/c"] {
+            expected.push(dir.path().join(subdir).into_os_string());
+        }
+        assert_eq!(watcher.watched_paths(), expected);
+
+        let file = dir.path().join("x/b/c/file");
+        fs::write(&file, "a").unwrap();
+        let events = collect_events(&mut watcher).await;
+        assert_eq!(
+            format!("{:?}", events[0]),
+            format!("{:?}", FileSystemEvent::FileCreated(file.into(), None))
+        );
+    }
+
+    #[tokio::test]
+    async fn paths_moved_out_of_the_tree_are_unwatched() {
+        let dir = tempfile::tempdir().unwrap();
+        let other = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        let mut watcher = FileSystemWatcherBuilder::new(dir.path().as_os_str())
+            .rewrite_moved_paths(true)
+            .build()
+            .unwrap();
+        collect_events(&mut watcher).await;
+
+        fs::rename(dir.path().join("a"), other.path().join("a")).unwrap();
+        collect_events(&mut watcher).await;
+        assert_eq!(
+            watcher.watched_paths(),
+            vec![dir.path().as_os_str().to_owned()]
+        );
+        fs::write(other.path().join("a/b/file"), "a").unwrap();
+        assert!(collect_events(&mut watcher).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn directories_moved_out_of_the_tree_are_reported_as_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let other = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("a")).unwrap();
+        let mut watcher = FileSystemWatcher::new(dir.path().as_os_str()).unwrap();
+        collect_events(&mut watcher).await;
+
+        // The directory does not exist anymore at its old location, so the type is taken from
+        // the MOVED_FROM event.
+        fs::rename(dir.path().join("a"), other.path().join("a")).unwrap();
+        let events = collect_events(&mut watcher).await;
+        assert_eq!(events.len(), 1);
+        match &events[0] {
+            FileSystemEvent::MovedFrom { path, .. } => assert_eq!(*path, dir.path().join("a")),
+            event => panic!("unexpected event: {:?}", event),
+        }
+        assert_eq!(events[0].is_dir(), Some(true));
+        assert_eq!(
+            watcher.watched_paths(),
+            vec![dir.path().as_os_str().to_owned()]
+        );
+    }
+
+    #[tokio::test]
+    async fn ignored_directories_produce_no_events() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("node_modules")).unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        let mut watcher = FileSystemWatcherBuilder::new(dir.path().as_os_str())
+            .ignore("**/node_modules/**")
+            .build()
+            .unwrap();
+        collect_events(&mut watcher).await;
+        let watched = watcher.watched_paths();
+        assert_eq!(watched.len(), 2);
+        assert!(!format!("{:?}", watched).contains("node_modules"));
+
+        fs::write(dir.path().join("node_modules/a"), "a").unwrap();
+        fs::write(dir.path().join("src/b"), "b").unwrap();
+        fs::create_dir(dir.path().join("src/node_modules")).unwrap();
+        fs::write(dir.path().join("src/node_modules/c"), "c").unwrap();
+        let events = collect_events(&mut watcher).await;
+        assert!(!format!("{:?}", events).contains("node_modules"));
+        assert!(events.iter().any(|event| match event {
+            FileSystemEvent::FileCreated(path, _) => Path::new(path).ends_with("src/b"),
+            _ => false,
+        }));
+    }
+}
@@ -1,31 +1,535 @@
+use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::task::{Context, Poll};
+#[cfg(feature = "delay")]
+use std::time::Duration;
+use std::time::SystemTime;
 
-use futures::stream::Stream;
+#[cfg(target_os = "linux")]
+use futures::future;
+use futures::stream::{Stream, StreamExt};
+use futures::task;
+use tokio::runtime;
+use tokio::sync::mpsc;
+#[cfg(feature = "delay")]
+use tokio::time;
 
+use crate::builder::WatcherOptions;
+#[cfg(target_os = "macos")]
+use crate::fsevents::FileSystemWatcherFSEvents;
 #[cfg(target_os = "linux")]
 use crate::inotify::FileSystemWatcherInotify;
+#[cfg(windows)]
+use crate::windows::FileSystemWatcherWindows;
+#[cfg(feature = "delay")]
+mod batched;
+mod builder;
+#[cfg(any(target_os = "macos", windows))]
+mod directory_tracker;
+mod event_filter;
+#[cfg(feature = "delay")]
 mod file_event_delay;
+#[cfg(target_os = "macos")]
+mod fsevents;
 #[cfg(target_os = "linux")]
 mod inotify;
+mod map_paths;
+#[cfg(feature = "notify-compat")]
+mod notify_compat;
+#[cfg(feature = "serde")]
+mod serialization;
+mod timestamped;
+#[cfg(windows)]
+mod windows;
+
+#[cfg(feature = "delay")]
+pub use batched::Batched;
+pub use builder::FileSystemWatcherBuilder;
+pub use event_filter::{EventFilter, EventKindSet};
+#[cfg(feature = "delay")]
+pub use file_event_delay::{FileEventDelay, OverflowPolicy};
+pub use map_paths::MapPaths;
+#[cfg(feature = "notify-compat")]
+pub use notify_compat::NotifyEvents;
+pub use timestamped::{Timed, Timestamped};
 
-pub use file_event_delay::FileEventDelay;
+/// Number of events buffered by the channel returned by `FileSystemWatcher::into_channel()`.
+pub const CHANNEL_CAPACITY: usize = 64;
 
+/// Size of the buffer used by `FileSystemWatcher::try_next_batch()` to read inotify events.
+#[cfg(target_os = "linux")]
+const READ_BUFFER_SIZE: usize = 4096;
+
+/// Stream of events for one or more monitored directories.
+///
+/// The watcher is `Send` and `Sync` on all platforms, so it can be moved into a separate task.
+/// Polling the stream and changing the set of monitored directories requires mutable access,
+/// whereas getters such as `is_active()` and `watched_paths()` can be called via a shared
+/// reference.
 pub struct FileSystemWatcher {
     #[cfg(target_os = "linux")]
     watcher: Pin<Box<FileSystemWatcherInotify>>,
+    #[cfg(target_os = "macos")]
+    watcher: Pin<Box<FileSystemWatcherFSEvents>>,
+    #[cfg(windows)]
+    watcher: Pin<Box<FileSystemWatcherWindows>>,
+    /// Set to false once the watcher has been closed or the last root directory stopped.
+    active: bool,
 }
 
 impl FileSystemWatcher {
+    /// Creates a watcher with the default configuration which recursively monitors the
+    /// directory at `path`.
+    ///
+    /// On Linux, `path` can also refer to a single file, in which case `FileWatched` is reported
+    /// instead of `DirectoryWatched` and the watcher stops once the file is removed or moved. On
+    /// Linux, `Error::NotFound` is returned if the path does not exist.
     pub fn new(path: &OsStr) -> Result<FileSystemWatcher, Error> {
+        FileSystemWatcherBuilder::new(path).build()
+    }
+
+    /// Same as `new()`, but accepts any type which can be converted to a path, e.g., `&str` or
+    /// `PathBuf`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<FileSystemWatcher, Error> {
+        FileSystemWatcher::new(path.as_ref().as_os_str())
+    }
+
+    /// Same as `new()`, but installs the watches for all existing subdirectories before
+    /// returning.
+    ///
+    /// The `DirectoryWatched` events and all other events generated during the initial scan are
+    /// discarded, so the stream only reports changes made after the function has returned. If the
+    /// root directory is removed during the scan, `Error::NotFound` is returned. Only available on
+    /// Linux.
+    #[cfg(target_os = "linux")]
+    pub async fn new_scanned(path: &OsStr) -> Result<FileSystemWatcher, Error> {
+        let mut watcher = FileSystemWatcher::new(path)?;
+        let scanned = watcher
+            .wait_for(|event| match event {
+                FileSystemEvent::ScanComplete | FileSystemEvent::Stopped(_) => true,
+                _ => false,
+            })
+            .await;
+        match scanned {
+            Some(FileSystemEvent::ScanComplete) => Ok(watcher),
+            _ => Err(Error::NotFound(path.to_owned())),
+        }
+    }
+
+    /// Creates a stream which reports the current contents of the directory at `path` once.
+    ///
+    /// The stream returns `DirectoryCreated` and `FileCreated` events for all files and
+    /// directories in the tree, followed by `ScanComplete`, and then ends. Changes made during
+    /// the scan might or might not be reflected. Only available on Linux.
+    #[cfg(target_os = "linux")]
+    pub fn snapshot(path: &OsStr) -> Result<FileSystemWatcher, Error> {
+        FileSystemWatcherBuilder::new(path)
+            .emit_existing(true)
+            .events(EventKindSet::DIRECTORY_CREATED | EventKindSet::FILE_CREATED)
+            .stop_after_scan(true)
+            .build()
+    }
+
+    /// Creates a watcher with the default configuration which recursively monitors multiple
+    /// directories.
+    ///
+    /// Compared to multiple separate watchers, only a single inotify instance is used and the
+    /// events for all directories are returned by the same stream. The directories should not be
+    /// nested.
+    pub fn new_multi(paths: &[&OsStr]) -> Result<FileSystemWatcher, Error> {
+        let (first, others) = match paths.split_first() {
+            Some(paths) => paths,
+            None => {
+                return Err(
+                    io::Error::new(io::ErrorKind::InvalidInput, "no directory specified").into(),
+                )
+            }
+        };
+        others
+            .iter()
+            .fold(FileSystemWatcherBuilder::new(first), |builder, path| {
+                builder.add_root(path)
+            })
+            .build()
+    }
+
+    /// Returns a builder to create a watcher with a non-default configuration.
+    pub fn builder(path: &OsStr) -> FileSystemWatcherBuilder {
+        FileSystemWatcherBuilder::new(path)
+    }
+
+    /// Stops monitoring the directory and removes all watches.
+    ///
+    /// Afterwards, the stream returns `None`. Calling this function multiple times has no effect.
+    pub fn close(&mut self) {
+        self.watcher.close();
+        self.active = false;
+    }
+
+    /// Stops monitoring the directory like `close()`, but first returns all events which have
+    /// already been generated by the operating system, so that they are not lost.
+    ///
+    /// No new watches are installed while the remaining events are collected, so no
+    /// `DirectoryWatched` events are returned. Only available on Linux.
+    #[cfg(target_os = "linux")]
+    pub async fn shutdown(mut self) -> Vec<FileSystemEvent> {
+        let mut events = Vec::new();
+        while let Some(event) = future::poll_fn(|cx| self.watcher.poll_remaining(cx)).await {
+            events.push(event);
+        }
+        self.close();
+        events
+    }
+
+    /// Returns whether the watcher still monitors any directories.
+    ///
+    /// Returns false once `close()` has been called or once the stream has returned a `Stopped`
+    /// event (or has ended), as no further events will be received afterwards.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Returns a snapshot of the directories which are currently being monitored.
+    ///
+    /// Directories are added once the corresponding `DirectoryWatched` event has been generated
+    /// and are removed when they are removed from the file system.
+    pub fn watched_paths(&self) -> Vec<OsString> {
+        self.watcher.watched_paths()
+    }
+
+    /// Returns counters which describe the activity of the watcher since it was created. Only
+    /// available on Linux.
+    #[cfg(target_os = "linux")]
+    pub fn stats(&self) -> Stats {
+        self.watcher.stats()
+    }
+
+    /// Returns the number of inotify watches which are currently installed.
+    ///
+    /// The number of watches per user is limited by `fs.inotify.max_user_watches`, so this
+    /// function can be used to monitor how close the watcher is to the limit, see also
+    /// `FileSystemWatcherBuilder::soft_watch_limit`. Watches for the parent directories of
+    /// removed root directories (see `FileSystemWatcherBuilder::resilient_root`) are included.
+    /// Only available on Linux.
+    #[cfg(target_os = "linux")]
+    pub fn watch_count(&self) -> usize {
+        self.watcher.watch_count()
+    }
+
+    /// Returns the number of directories which have been found, but are not monitored yet.
+    ///
+    /// Watches are installed while the stream is polled, so during the initial scan of a large
+    /// tree this number can be used to show the progress. Once it reaches zero, all directories
+    /// are monitored. Only available on Linux.
+    #[cfg(target_os = "linux")]
+    pub fn pending_watch_count(&self) -> usize {
+        self.watcher.pending_watch_count()
+    }
+
+    /// Waits for the next event, but gives up after `timeout`.
+    ///
+    /// Returns `None` if no event was received within the timeout or if the watcher has been
+    /// closed. A timeout does not stop the watcher, so the caller can simply call this function
+    /// again to wait for further events. Requires the `delay` feature.
+    #[cfg(feature = "delay")]
+    pub async fn next_event_timeout(&mut self, timeout: Duration) -> Option<FileSystemEvent> {
+        match time::timeout(timeout, self.next()).await {
+            Ok(event) => event,
+            Err(_) => None,
+        }
+    }
+
+    /// Returns all events which are available right away without waiting for further events.
+    ///
+    /// The stream is polled until it has no more events, so the function can be called
+    /// periodically from an event loop which is not driven by tokio. New watches are installed
+    /// while the stream is polled, so the first call usually returns the `DirectoryWatched`
+    /// events of the initial scan. Returns an empty vector if no events are available or if the
+    /// stream has ended.
+    ///
+    /// No tokio runtime is required. On Linux, the events are read from the file descriptor via
+    /// `read_events_into()` unless the watcher has already been polled as a stream, so a watcher
+    /// which is driven by this function must not be polled as a stream afterwards.
+    pub fn try_next_batch(&mut self) -> Vec<FileSystemEvent> {
         #[cfg(target_os = "linux")]
-        let watcher = Box::pin(FileSystemWatcherInotify::new(path)?);
+        {
+            if !self.watcher.uses_event_stream() {
+                let mut buffer = vec![0; READ_BUFFER_SIZE];
+                return match self.read_events_into(&mut buffer) {
+                    Ok(events) => events,
+                    Err(e) => {
+                        // The watcher has been closed after the error.
+                        self.active = false;
+                        vec![FileSystemEvent::Error(e)]
+                    }
+                };
+            }
+        }
+        // The caller polls again on its own schedule, so nobody needs to be woken up.
+        let mut cx = Context::from_waker(task::noop_waker_ref());
+        let mut events = Vec::new();
+        while let Poll::Ready(Some(event)) = self.poll_next_unpin(&mut cx) {
+            events.push(event);
+        }
+        events
+    }
+
+    /// Returns the file descriptor of the inotify instance, so that the watcher can be driven by
+    /// a custom event loop (e.g., epoll or mio) via `read_events_into()`. Only available on
+    /// Linux.
+    #[cfg(target_os = "linux")]
+    pub fn as_raw_fd(&self) -> RawFd {
+        self.watcher.as_raw_fd()
+    }
+
+    /// Reads the events which are currently available without blocking and without requiring a
+    /// tokio runtime.
+    ///
+    /// This function is meant for event loops which wait until the file descriptor returned by
+    /// `as_raw_fd()` becomes readable and then call this function. `buffer` receives the raw
+    /// inotify events and needs to hold at least one event with a maximum-length file name, a
+    /// size of 4096 bytes is sufficient. The function also installs the watches for new
+    /// directories, so it has to be called once right after the watcher has been created to
+    /// perform the initial scan, and again after `watch()`, `rescan()` or `resume()`, as these
+    /// do not make the file descriptor readable. All events available in the kernel queue are
+    /// read, even if they do not fit into `buffer` at once. If the stream has ended, an empty
+    /// vector is returned.
+    ///
+    /// Both this function and the stream read from the same file descriptor, so an error is
+    /// returned if the watcher has already been polled as a stream, and the stream ends once this
+    /// function has been called. `UnreadableDirectoryPolicy::Retry` requires a tokio runtime.
+    /// Only available on Linux.
+    #[cfg(target_os = "linux")]
+    pub fn read_events_into(&mut self, buffer: &mut [u8]) -> Result<Vec<FileSystemEvent>, Error> {
+        let events = self.watcher.read_events_into(buffer)?;
+        if events
+            .iter()
+            .any(|event| event.kind() == EventKind::Stopped)
+        {
+            self.active = false;
+        }
+        Ok(events)
+    }
+
+    /// Waits for the first event for which `predicate` returns true, discarding all events before
+    /// it.
+    ///
+    /// Returns `None` if the stream ends without a matching event. The function waits
+    /// indefinitely, so `tokio::time::timeout()` can be used to limit the time, e.g., when waiting
+    /// for a PID file to be created:
+    ///
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # use fswatcher::{FileSystemEvent, FileSystemWatcher};
+    /// # async fn example(watcher: &mut FileSystemWatcher) {
+    /// let created = tokio::time::timeout(
+    ///     Duration::from_secs(10),
+    ///     watcher.wait_for(|event| match event {
+    ///         FileSystemEvent::FileCreated(path, _) => path == "/run/app/app.pid",
+    ///         _ => false,
+    ///     }),
+    /// )
+    /// .await;
+    /// # }
+    /// ```
+    pub async fn wait_for<F>(&mut self, predicate: F) -> Option<FileSystemEvent>
+    where
+        F: Fn(&FileSystemEvent) -> bool,
+    {
+        while let Some(event) = self.next().await {
+            if predicate(&event) {
+                return Some(event);
+            }
+        }
+        None
+    }
+
+    /// Returns a stream which only contains the events of the specified types.
+    ///
+    /// `Stopped`, `RootStopped`, `ScanComplete`, `Overflow`, `WatchFailed`, `WatchLimitWarning`,
+    /// `NonUtf8Path` and `Error` events are always passed through.
+    pub fn events_of_kinds(self, kinds: EventKindSet) -> EventFilter<FileSystemWatcher> {
+        EventFilter::new(self, kinds)
+    }
+
+    /// Returns a stream which delays the events by at least `min_delay` and combines them, e.g.,
+    /// `FileSystemWatcher::open(path)?.with_delay(Duration::from_millis(200))`.
+    ///
+    /// See `FileEventDelay` for details. Requires the `delay` feature.
+    #[cfg(feature = "delay")]
+    pub fn with_delay(self, min_delay: Duration) -> FileEventDelay<FileSystemWatcher> {
+        FileEventDelay::new(self, min_delay)
+    }
+
+    /// Returns a stream which collects the events and returns them as a batch once no new event
+    /// has been received for `quiet`.
+    ///
+    /// See `Batched` for details. Requires the `delay` feature.
+    #[cfg(feature = "delay")]
+    pub fn batched(self, quiet: Duration) -> Batched<FileSystemWatcher> {
+        Batched::new(self, quiet)
+    }
+
+    /// Returns a stream which records when each event was received, see `Timed`.
+    ///
+    /// To stamp delayed events when they leave the delay, wrap the `FileEventDelay` instead, e.g.,
+    /// `Timestamped::new(watcher.with_delay(delay))`.
+    pub fn with_timestamps(self) -> Timestamped<FileSystemWatcher> {
+        Timestamped::new(self)
+    }
+
+    /// Returns a stream which applies `f` to all paths of the events, see
+    /// `FileSystemEvent::map_paths()`.
+    pub fn map_paths<F>(self, f: F) -> MapPaths<FileSystemWatcher, F>
+    where
+        F: FnMut(OsString) -> OsString,
+    {
+        MapPaths::new(self, f)
+    }
+
+    /// Returns a stream which reports all paths relative to `root`, e.g., relative to the watched
+    /// directory.
+    ///
+    /// `root` itself is reported as an empty path, paths which are not located below `root` are
+    /// returned unchanged.
+    pub fn relative_to(
+        self,
+        root: PathBuf,
+    ) -> MapPaths<FileSystemWatcher, impl FnMut(OsString) -> OsString> {
+        self.map_paths(move |path| match Path::new(&path).strip_prefix(&root) {
+            Ok(relative) => relative.as_os_str().to_owned(),
+            Err(_) => path,
+        })
+    }
+
+    /// Returns a stream which converts the events into the event type of the `notify` crate.
+    ///
+    /// See `NotifyEvents` for details. Requires the `notify-compat` feature.
+    #[cfg(feature = "notify-compat")]
+    pub fn into_notify_events(self) -> NotifyEvents<FileSystemWatcher> {
+        NotifyEvents::new(self)
+    }
+
+    /// Converts the watcher into an iterator which blocks until the next event is available.
+    ///
+    /// This function is meant for applications which do not use tokio - the stream is driven by
+    /// a single-threaded runtime which is created by this function. The iterator ends once the
+    /// stream ends.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the runtime cannot be created, or if the function is called from within an
+    /// asynchronous context.
+    pub fn into_blocking_iter(self) -> impl Iterator<Item = FileSystemEvent> {
+        let mut runtime = runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .expect("could not create tokio runtime");
+        let mut watcher = self;
+        std::iter::from_fn(move || runtime.block_on(watcher.next()))
+    }
+
+    /// Spawns a task which drives the watcher and returns a channel which receives the events.
+    ///
+    /// The channel can buffer `CHANNEL_CAPACITY` events. If the receiver does not keep up, the
+    /// task stops polling the watcher until there is space again, so the events are buffered by
+    /// the operating system in the meantime (and might overflow, see `FileSystemEvent::Overflow`).
+    /// The channel is closed once the stream ends. If the receiver is dropped, the task stops
+    /// and drops the watcher as soon as it tries to forward the next event.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the function is not called from within a tokio runtime.
+    pub fn into_channel(self) -> mpsc::Receiver<FileSystemEvent> {
+        let (mut sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        let mut watcher = self;
+        tokio::spawn(async move {
+            while let Some(event) = watcher.next().await {
+                if sender.send(event).await.is_err() {
+                    // The receiver was dropped.
+                    break;
+                }
+            }
+        });
+        receiver
+    }
+
+    /// Starts monitoring an additional directory while the watcher is running.
+    ///
+    /// A `DirectoryWatched` event is generated once the directory is being monitored. Only
+    /// available on Linux.
+    #[cfg(target_os = "linux")]
+    pub fn watch(&mut self, path: &OsStr) {
+        self.watcher.watch(path);
+    }
+
+    /// Walks the monitored directories again, for example after an `Overflow` event.
+    ///
+    /// Watches are installed for all directories which are not monitored yet, generating
+    /// `DirectoryWatched` events. Directories which are already monitored are not reported again.
+    /// If `FileSystemWatcherBuilder::emit_existing` is set, `DirectoryCreated` and `FileCreated`
+    /// events are generated for the current contents of all directories, so that the caller can
+    /// reconcile its state. Only available on Linux.
+    #[cfg(target_os = "linux")]
+    pub fn rescan(&mut self) {
+        self.watcher.rescan();
+    }
+
+    /// Temporarily stops delivering events without removing any watches.
+    ///
+    /// While the watcher is paused, no new watches are installed and, depending on `policy`,
+    /// events are either kept until `resume()` is called or discarded. Buffered events are kept
+    /// in the queue of the operating system, so pausing does not allocate any memory and does not
+    /// consume CPU time. The size of that queue is limited (`fs.inotify.max_queued_events`),
+    /// though, so if too many events occur while the watcher is paused, an `Overflow` event is
+    /// returned after resuming. Discarding events still requires reading them, but `Stopped`,
+    /// `RootStopped`, `Overflow` and error events are returned even while the watcher is paused.
+    /// Only available on Linux.
+    #[cfg(target_os = "linux")]
+    pub fn pause(&mut self, policy: PausePolicy) {
+        self.watcher.pause(policy);
+    }
+
+    /// Resumes delivering events after `pause()`. Buffered events are returned first, afterwards
+    /// watches for directories created in the meantime are installed. Only available on Linux.
+    #[cfg(target_os = "linux")]
+    pub fn resume(&mut self) {
+        self.watcher.resume();
+    }
+
+    /// Stops monitoring a directory and all its subdirectories. Only available on Linux.
+    #[cfg(target_os = "linux")]
+    pub fn unwatch(&mut self, path: &OsStr) {
+        self.watcher.unwatch(path);
+    }
+
+    fn with_options(
+        roots: &[OsString],
+        options: WatcherOptions,
+    ) -> Result<FileSystemWatcher, Error> {
+        #[cfg(target_os = "linux")]
+        let watcher = Box::pin(FileSystemWatcherInotify::new(roots, options)?);
+        #[cfg(target_os = "macos")]
+        let watcher = Box::pin(FileSystemWatcherFSEvents::new(roots)?);
+        #[cfg(windows)]
+        let watcher = Box::pin(FileSystemWatcherWindows::new(roots)?);
 
         #[cfg(not(target_os = "linux"))]
+        let _ = options;
+        #[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
         panic!("Not yet implemented.");
 
-        Ok(FileSystemWatcher { watcher })
+        Ok(FileSystemWatcher {
+            watcher,
+            active: true,
+        })
     }
 }
 
@@ -36,36 +540,651 @@ impl Stream for FileSystemWatcher {
         // Safe, as we will not move self_.
         let self_ = unsafe { self.get_unchecked_mut() };
 
-        Pin::as_mut(&mut self_.watcher).poll_next(cx)
+        let result = Pin::as_mut(&mut self_.watcher).poll_next(cx);
+        if let Poll::Ready(None) | Poll::Ready(Some(FileSystemEvent::Stopped(_))) = result {
+            self_.active = false;
+        }
+        result
     }
 }
 
+/// Event generated when the monitored directories change.
+///
+/// If the `serde` feature is enabled, events implement `Serialize` and `Deserialize`. Paths are
+/// serialized as (lossily converted) UTF-8 strings and I/O errors as their message, so
+/// deserialization of non-UTF-8 paths and errors does not yield the original values.
 #[derive(Debug)]
 pub enum FileSystemEvent {
+    /// Monitoring stopped, no further events will be received. If the watcher monitors multiple
+    /// root directories, this event is generated once the last root directory stopped.
     Stopped(StopReason),
+    /// Monitoring of one of multiple root directories stopped. The other root directories are
+    /// still being monitored.
+    RootStopped(OsString, StopReason),
     DirectoryWatched(OsString),
+    /// Multiple directories are being monitored. Generated instead of individual
+    /// `DirectoryWatched` events if `FileSystemWatcherBuilder::watch_batch_size` is larger than 1.
+    /// Only generated on Linux.
+    DirectoriesWatched(Vec<OsString>),
+    /// A root which is a file instead of a directory is being monitored. Only supported on Linux.
+    FileWatched(OsString),
+    /// All directories which existed when the watcher was created are being monitored. The event
+    /// is generated exactly once, all later events are caused by changes since then. Only
+    /// generated on Linux.
+    ScanComplete,
     /// A directory was created. Note that the directory does not need to be
-    /// empty - the caller has to check for existing file contents. Existing
+    /// empty - the caller has to check for existing file contents unless
+    /// `FileSystemWatcherBuilder::emit_existing` is set. Existing
     /// subdirectories are automatically monitored for changes.
     DirectoryCreated(OsString),
+    /// The metadata of a directory (permissions, ownership, timestamps, ...) was changed.
     DirectoryModified(OsString),
     DirectoryRemoved(OsString),
+    /// A directory was moved within the watched tree, the first path is the old location, the
+    /// second path the new one. Directories moved into or out of the watched tree are reported as
+    /// `MovedTo` and `MovedFrom`, respectively.
     DirectoryMoved(OsString, OsString),
-    FileCreated(OsString),
-    FileModified(OsString),
+    /// A file was created. The metadata is only available if
+    /// `FileSystemWatcherBuilder::file_metadata` is set, see `FileMetadata`.
+    FileCreated(OsString, Option<FileMetadata>),
+    /// The content of a file was modified. The metadata is only available if
+    /// `FileSystemWatcherBuilder::file_metadata` is set, see `FileMetadata`. Events generated by
+    /// `FileEventDelay` for atomic saves never carry metadata.
+    FileModified(OsString, Option<FileMetadata>),
+    /// The metadata of a file (permissions, ownership, timestamps, ...) was changed without
+    /// modifying its content. Only reported if `FileSystemWatcherBuilder::fold_attribute_changes`
+    /// is not set, otherwise `FileModified` is used instead.
+    FileAttributesChanged(OsString),
+    /// A file which was opened for writing was closed, so the writer most likely finished
+    /// writing. Only generated on Linux and only if selected via
+    /// `FileSystemWatcherBuilder::events`.
+    FileWriteClosed(OsString),
+    /// A file was opened. Only generated on Linux and only if selected via
+    /// `FileSystemWatcherBuilder::events`.
+    FileOpened(OsString),
+    /// A file was read. Only generated on Linux and only if selected via
+    /// `FileSystemWatcherBuilder::events`.
+    FileAccessed(OsString),
+    /// A file which was not opened for writing was closed. Only generated on Linux and only if
+    /// selected via `FileSystemWatcherBuilder::events`.
+    FileClosed(OsString),
     FileRemoved(OsString),
+    /// A file was moved within the watched tree, the first path is the old location, the second
+    /// path the new one. Files moved into or out of the watched tree are reported as `MovedTo`
+    /// and `MovedFrom`, respectively.
     FileMoved(OsString, OsString),
+    /// A file or directory was moved away from `path`, but the corresponding `MovedTo` event was
+    /// not available at the same time. Usually, the entry was moved out of the watched tree and
+    /// this event can be treated like a removal. `FileEventDelay` combines this event with a
+    /// later `MovedTo` event with the same cookie.
+    MovedFrom {
+        path: OsString,
+        cookie: u32,
+        is_dir: bool,
+    },
+    /// A file or directory was moved to `path`, but no corresponding `MovedFrom` event was
+    /// received before. Usually, the entry was moved into the watched tree and this event can be
+    /// treated like a creation.
+    MovedTo {
+        path: OsString,
+        cookie: u32,
+        is_dir: bool,
+    },
+    /// The event queue of the operating system or of the watcher (see
+    /// `FileSystemWatcherBuilder::max_queued_events`) overflowed and events were lost. The caller
+    /// has to rescan the watched directories to detect the missed changes, see
+    /// `FileSystemWatcher::rescan`.
+    Overflow,
+    /// Unprocessed event from the operating system, only generated if
+    /// `FileSystemWatcherBuilder::raw_events` is set. On Linux, `mask` and `cookie` are the values
+    /// reported by inotify and `path` is empty if the event does not refer to a watched path.
+    Raw {
+        path: OsString,
+        mask: u32,
+        cookie: u32,
+    },
+    /// The path or its subdirectories could not be monitored, so parts of the tree might not be
+    /// monitored.
+    WatchFailed(OsString, Error),
+    /// The number of installed watches exceeded the soft limit set via
+    /// `FileSystemWatcherBuilder::soft_watch_limit`, the payload is the current number of
+    /// watches. The event is generated again if the number drops below the limit and exceeds it
+    /// again later. Only generated on Linux.
+    WatchLimitWarning(usize),
+    /// An event of the specified type refers to a path which is not valid UTF-8. Generated
+    /// instead of the original event if `FileSystemWatcherBuilder::strict_paths` is set. The path
+    /// is passed through unmodified, so that the actual file name can still be recovered (e.g.,
+    /// via `std::os::unix::ffi::OsStrExt::as_bytes`). Only generated on Linux.
+    NonUtf8Path(OsString, EventKind),
     Error(Error),
 }
 
-#[derive(Debug)]
+impl FileSystemEvent {
+    /// Returns the type of the event without the payload.
+    pub fn kind(&self) -> EventKind {
+        match self {
+            FileSystemEvent::Stopped(_) => EventKind::Stopped,
+            FileSystemEvent::RootStopped(_, _) => EventKind::RootStopped,
+            FileSystemEvent::DirectoryWatched(_) => EventKind::DirectoryWatched,
+            FileSystemEvent::DirectoriesWatched(_) => EventKind::DirectoriesWatched,
+            FileSystemEvent::FileWatched(_) => EventKind::FileWatched,
+            FileSystemEvent::ScanComplete => EventKind::ScanComplete,
+            FileSystemEvent::DirectoryCreated(_) => EventKind::DirectoryCreated,
+            FileSystemEvent::DirectoryModified(_) => EventKind::DirectoryModified,
+            FileSystemEvent::DirectoryRemoved(_) => EventKind::DirectoryRemoved,
+            FileSystemEvent::DirectoryMoved(_, _) => EventKind::DirectoryMoved,
+            FileSystemEvent::FileCreated(_, _) => EventKind::FileCreated,
+            FileSystemEvent::FileModified(_, _) => EventKind::FileModified,
+            FileSystemEvent::FileAttributesChanged(_) => EventKind::FileAttributesChanged,
+            FileSystemEvent::FileWriteClosed(_) => EventKind::FileWriteClosed,
+            FileSystemEvent::FileOpened(_) => EventKind::FileOpened,
+            FileSystemEvent::FileAccessed(_) => EventKind::FileAccessed,
+            FileSystemEvent::FileClosed(_) => EventKind::FileClosed,
+            FileSystemEvent::FileRemoved(_) => EventKind::FileRemoved,
+            FileSystemEvent::FileMoved(_, _) => EventKind::FileMoved,
+            FileSystemEvent::MovedFrom { .. } => EventKind::MovedFrom,
+            FileSystemEvent::MovedTo { .. } => EventKind::MovedTo,
+            FileSystemEvent::Overflow => EventKind::Overflow,
+            FileSystemEvent::Raw { .. } => EventKind::Raw,
+            FileSystemEvent::WatchFailed(_, _) => EventKind::WatchFailed,
+            FileSystemEvent::WatchLimitWarning(_) => EventKind::WatchLimitWarning,
+            FileSystemEvent::NonUtf8Path(_, _) => EventKind::NonUtf8Path,
+            FileSystemEvent::Error(_) => EventKind::Error,
+        }
+    }
+
+    /// Returns the path the event refers to. For `FileMoved` and `DirectoryMoved` events, the new
+    /// location is returned.
+    pub fn path(&self) -> Option<&Path> {
+        match self.paths() {
+            (_, Some(to)) => Some(to),
+            (path, None) => path,
+        }
+    }
+
+    /// Returns the paths the event refers to. For `FileMoved` and `DirectoryMoved` events, the
+    /// old and the new location are returned, all other events only refer to a single path.
+    /// `DirectoriesWatched` does not refer to a single path, so no path is returned.
+    pub fn paths(&self) -> (Option<&Path>, Option<&Path>) {
+        match self {
+            FileSystemEvent::Stopped(_)
+            | FileSystemEvent::DirectoriesWatched(_)
+            | FileSystemEvent::ScanComplete
+            | FileSystemEvent::Overflow
+            | FileSystemEvent::WatchLimitWarning(_)
+            | FileSystemEvent::Error(_) => (None, None),
+            FileSystemEvent::DirectoryMoved(from, to) | FileSystemEvent::FileMoved(from, to) => {
+                (Some(Path::new(from)), Some(Path::new(to)))
+            }
+            FileSystemEvent::RootStopped(path, _)
+            | FileSystemEvent::DirectoryWatched(path)
+            | FileSystemEvent::FileWatched(path)
+            | FileSystemEvent::DirectoryCreated(path)
+            | FileSystemEvent::DirectoryModified(path)
+            | FileSystemEvent::DirectoryRemoved(path)
+            | FileSystemEvent::FileCreated(path, _)
+            | FileSystemEvent::FileModified(path, _)
+            | FileSystemEvent::FileAttributesChanged(path)
+            | FileSystemEvent::FileWriteClosed(path)
+            | FileSystemEvent::FileOpened(path)
+            | FileSystemEvent::FileAccessed(path)
+            | FileSystemEvent::FileClosed(path)
+            | FileSystemEvent::FileRemoved(path)
+            | FileSystemEvent::MovedFrom { path, .. }
+            | FileSystemEvent::MovedTo { path, .. }
+            | FileSystemEvent::Raw { path, .. }
+            | FileSystemEvent::WatchFailed(path, _)
+            | FileSystemEvent::NonUtf8Path(path, _) => (Some(Path::new(path)), None),
+        }
+    }
+
+    /// Returns whether the event refers to a directory or to a file.
+    ///
+    /// For `MovedFrom` and `MovedTo` events, the type is taken from the half of the move which was
+    /// observed, so it is also known if the entry was moved out of the watched tree. Returns
+    /// `None` for events which do not refer to a single file or directory.
+    pub fn is_dir(&self) -> Option<bool> {
+        match self {
+            FileSystemEvent::DirectoryWatched(_)
+            | FileSystemEvent::DirectoriesWatched(_)
+            | FileSystemEvent::DirectoryCreated(_)
+            | FileSystemEvent::DirectoryModified(_)
+            | FileSystemEvent::DirectoryRemoved(_)
+            | FileSystemEvent::DirectoryMoved(_, _) => Some(true),
+            FileSystemEvent::FileWatched(_)
+            | FileSystemEvent::FileCreated(_, _)
+            | FileSystemEvent::FileModified(_, _)
+            | FileSystemEvent::FileAttributesChanged(_)
+            | FileSystemEvent::FileWriteClosed(_)
+            | FileSystemEvent::FileOpened(_)
+            | FileSystemEvent::FileAccessed(_)
+            | FileSystemEvent::FileClosed(_)
+            | FileSystemEvent::FileRemoved(_)
+            | FileSystemEvent::FileMoved(_, _) => Some(false),
+            FileSystemEvent::MovedFrom { is_dir, .. } | FileSystemEvent::MovedTo { is_dir, .. } => {
+                Some(*is_dir)
+            }
+            FileSystemEvent::Stopped(_)
+            | FileSystemEvent::RootStopped(_, _)
+            | FileSystemEvent::ScanComplete
+            | FileSystemEvent::Overflow
+            | FileSystemEvent::Raw { .. }
+            | FileSystemEvent::WatchFailed(_, _)
+            | FileSystemEvent::WatchLimitWarning(_)
+            | FileSystemEvent::NonUtf8Path(_, _)
+            | FileSystemEvent::Error(_) => None,
+        }
+    }
+
+    /// Returns the first path of the event which is not valid UTF-8, if any.
+    ///
+    /// Such paths cannot be converted to strings without losing information, e.g., when the event
+    /// is serialized. See `FileSystemWatcherBuilder::strict_paths`.
+    pub fn non_utf8_path(&self) -> Option<&Path> {
+        if let FileSystemEvent::DirectoriesWatched(paths) = self {
+            return paths
+                .iter()
+                .map(Path::new)
+                .find(|path| path.to_str().is_none());
+        }
+        let (first, second) = self.paths();
+        first
+            .into_iter()
+            .chain(second)
+            .find(|path| path.to_str().is_none())
+    }
+
+    /// Returns whether a `FileMoved` or `DirectoryMoved` event describes a rename within the same
+    /// directory, so that only the file name changed.
+    ///
+    /// Returns `false` for all other events.
+    pub fn same_parent(&self) -> bool {
+        match self {
+            FileSystemEvent::FileMoved(from, to) | FileSystemEvent::DirectoryMoved(from, to) => {
+                Path::new(from).parent() == Path::new(to).parent()
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the cookie of a `MovedFrom` or `MovedTo` event which can be used to correlate the
+    /// two halves of a move.
+    ///
+    /// Moves for which both halves are available at the same time are already combined into
+    /// `FileMoved` and `DirectoryMoved` events, so other events do not carry a cookie.
+    pub fn cookie(&self) -> Option<u32> {
+        match self {
+            FileSystemEvent::MovedFrom { cookie, .. } | FileSystemEvent::MovedTo { cookie, .. } => {
+                Some(*cookie)
+            }
+            _ => None,
+        }
+    }
+
+    /// Applies `f` to all paths of the event, e.g., to make them relative to the watched
+    /// directory.
+    ///
+    /// Both paths of `FileMoved` and `DirectoryMoved` events and all paths of
+    /// `DirectoriesWatched` events are transformed. Events without paths and the paths within
+    /// `Error` values are left untouched.
+    pub fn map_paths<F>(self, mut f: F) -> FileSystemEvent
+    where
+        F: FnMut(OsString) -> OsString,
+    {
+        match self {
+            FileSystemEvent::RootStopped(path, reason) => {
+                FileSystemEvent::RootStopped(f(path), reason)
+            }
+            FileSystemEvent::DirectoryWatched(path) => FileSystemEvent::DirectoryWatched(f(path)),
+            FileSystemEvent::DirectoriesWatched(paths) => {
+                FileSystemEvent::DirectoriesWatched(paths.into_iter().map(f).collect())
+            }
+            FileSystemEvent::FileWatched(path) => FileSystemEvent::FileWatched(f(path)),
+            FileSystemEvent::DirectoryCreated(path) => FileSystemEvent::DirectoryCreated(f(path)),
+            FileSystemEvent::DirectoryModified(path) => FileSystemEvent::DirectoryModified(f(path)),
+            FileSystemEvent::DirectoryRemoved(path) => FileSystemEvent::DirectoryRemoved(f(path)),
+            FileSystemEvent::DirectoryMoved(from, to) => {
+                let from = f(from);
+                FileSystemEvent::DirectoryMoved(from, f(to))
+            }
+            FileSystemEvent::FileCreated(path, metadata) => {
+                FileSystemEvent::FileCreated(f(path), metadata)
+            }
+            FileSystemEvent::FileModified(path, metadata) => {
+                FileSystemEvent::FileModified(f(path), metadata)
+            }
+            FileSystemEvent::FileAttributesChanged(path) => {
+                FileSystemEvent::FileAttributesChanged(f(path))
+            }
+            FileSystemEvent::FileWriteClosed(path) => FileSystemEvent::FileWriteClosed(f(path)),
+            FileSystemEvent::FileOpened(path) => FileSystemEvent::FileOpened(f(path)),
+            FileSystemEvent::FileAccessed(path) => FileSystemEvent::FileAccessed(f(path)),
+            FileSystemEvent::FileClosed(path) => FileSystemEvent::FileClosed(f(path)),
+            FileSystemEvent::FileRemoved(path) => FileSystemEvent::FileRemoved(f(path)),
+            FileSystemEvent::FileMoved(from, to) => {
+                let from = f(from);
+                FileSystemEvent::FileMoved(from, f(to))
+            }
+            FileSystemEvent::MovedFrom {
+                path,
+                cookie,
+                is_dir,
+            } => FileSystemEvent::MovedFrom {
+                path: f(path),
+                cookie,
+                is_dir,
+            },
+            FileSystemEvent::MovedTo {
+                path,
+                cookie,
+                is_dir,
+            } => FileSystemEvent::MovedTo {
+                path: f(path),
+                cookie,
+                is_dir,
+            },
+            FileSystemEvent::Raw { path, mask, cookie } => FileSystemEvent::Raw {
+                path: f(path),
+                mask,
+                cookie,
+            },
+            FileSystemEvent::WatchFailed(path, error) => {
+                FileSystemEvent::WatchFailed(f(path), error)
+            }
+            FileSystemEvent::NonUtf8Path(path, kind) => FileSystemEvent::NonUtf8Path(f(path), kind),
+            event @ FileSystemEvent::Stopped(_)
+            | event @ FileSystemEvent::ScanComplete
+            | event @ FileSystemEvent::Overflow
+            | event @ FileSystemEvent::WatchLimitWarning(_)
+            | event @ FileSystemEvent::Error(_) => event,
+        }
+    }
+}
+
+impl fmt::Display for FileSystemEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FileSystemEvent::Stopped(reason) => write!(f, "stopped ({})", reason),
+            FileSystemEvent::RootStopped(path, reason) => {
+                write!(
+                    f,
+                    "stopped watching {} ({})",
+                    path.to_string_lossy(),
+                    reason
+                )
+            }
+            FileSystemEvent::DirectoryWatched(path) => {
+                write!(f, "watching directory {}", path.to_string_lossy())
+            }
+            FileSystemEvent::DirectoriesWatched(paths) => {
+                write!(f, "watching {} directories", paths.len())
+            }
+            FileSystemEvent::FileWatched(path) => {
+                write!(f, "watching file {}", path.to_string_lossy())
+            }
+            FileSystemEvent::ScanComplete => write!(f, "initial scan complete"),
+            FileSystemEvent::DirectoryCreated(path) => {
+                write!(f, "created directory {}", path.to_string_lossy())
+            }
+            FileSystemEvent::DirectoryModified(path) => {
+                write!(f, "modified directory {}", path.to_string_lossy())
+            }
+            FileSystemEvent::DirectoryRemoved(path) => {
+                write!(f, "removed directory {}", path.to_string_lossy())
+            }
+            FileSystemEvent::FileCreated(path, _) => {
+                write!(f, "created file {}", path.to_string_lossy())
+            }
+            FileSystemEvent::FileModified(path, _) => {
+                write!(f, "modified file {}", path.to_string_lossy())
+            }
+            FileSystemEvent::FileAttributesChanged(path) => {
+                write!(f, "changed attributes of file {}", path.to_string_lossy())
+            }
+            FileSystemEvent::FileWriteClosed(path) => {
+                write!(f, "finished writing file {}", path.to_string_lossy())
+            }
+            FileSystemEvent::FileOpened(path) => {
+                write!(f, "opened file {}", path.to_string_lossy())
+            }
+            FileSystemEvent::FileAccessed(path) => {
+                write!(f, "accessed file {}", path.to_string_lossy())
+            }
+            FileSystemEvent::FileClosed(path) => {
+                write!(f, "closed file {}", path.to_string_lossy())
+            }
+            FileSystemEvent::FileRemoved(path) => {
+                write!(f, "removed file {}", path.to_string_lossy())
+            }
+            FileSystemEvent::DirectoryMoved(from, to) | FileSystemEvent::FileMoved(from, to) => {
+                write!(
+                    f,
+                    "moved {} -> {}",
+                    from.to_string_lossy(),
+                    to.to_string_lossy()
+                )
+            }
+            FileSystemEvent::MovedFrom { path, .. } => {
+                write!(f, "moved {} away", path.to_string_lossy())
+            }
+            FileSystemEvent::MovedTo { path, .. } => {
+                write!(f, "moved {} in", path.to_string_lossy())
+            }
+            FileSystemEvent::Overflow => write!(f, "event queue overflow"),
+            FileSystemEvent::Raw { path, mask, cookie } => write!(
+                f,
+                "raw event {:#x} for {} (cookie {})",
+                mask,
+                path.to_string_lossy(),
+                cookie
+            ),
+            FileSystemEvent::WatchFailed(path, error) => {
+                write!(f, "failed to watch {}: {}", path.to_string_lossy(), error)
+            }
+            FileSystemEvent::WatchLimitWarning(count) => {
+                write!(f, "{} watches installed, soft limit exceeded", count)
+            }
+            FileSystemEvent::NonUtf8Path(path, kind) => {
+                write!(
+                    f,
+                    "{:?} for non-UTF-8 path {}",
+                    kind,
+                    path.to_string_lossy()
+                )
+            }
+            FileSystemEvent::Error(error) => write!(f, "error: {}", error),
+        }
+    }
+}
+
+/// Type of a `FileSystemEvent`, see `FileSystemEvent::kind()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum EventKind {
+    Stopped,
+    RootStopped,
+    DirectoryWatched,
+    DirectoriesWatched,
+    FileWatched,
+    ScanComplete,
+    DirectoryCreated,
+    DirectoryModified,
+    DirectoryRemoved,
+    DirectoryMoved,
+    FileCreated,
+    FileModified,
+    FileAttributesChanged,
+    FileWriteClosed,
+    FileOpened,
+    FileAccessed,
+    FileClosed,
+    FileRemoved,
+    FileMoved,
+    MovedFrom,
+    MovedTo,
+    Overflow,
+    Raw,
+    WatchFailed,
+    WatchLimitWarning,
+    NonUtf8Path,
+    Error,
+}
+
+/// Metadata of a created or modified file, see `FileSystemWatcherBuilder::file_metadata`.
+///
+/// The metadata is queried when the event is read from the operating system, not when the file
+/// is changed. The file may have been modified, replaced or removed in the meantime, so the
+/// metadata can belong to a different file than the one which caused the event, and it is
+/// missing if the file is already gone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FileMetadata {
+    /// Inode number of the file. Together with `device`, the inode identifies the file, so it can
+    /// be used to detect hard links or to correlate moves.
+    pub inode: u64,
+    /// Device containing the file.
+    pub device: u64,
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// Time of the last modification of the content, if supported by the file system.
+    pub modified: Option<SystemTime>,
+}
+
+/// Cumulative counters of a watcher, see `FileSystemWatcher::stats()`.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    /// Number of events read from the operating system, including the ones which did not result
+    /// in a `FileSystemEvent`.
+    pub raw_events: u64,
+    /// Number of events returned by the stream, by type.
+    pub events: HashMap<EventKind, u64>,
+    /// Number of watches installed for directories or files.
+    pub watches_added: u64,
+    /// Number of watches removed, either because the directory was removed or because it is not
+    /// monitored anymore.
+    pub watches_removed: u64,
+    /// Number of events and directories dropped because the limit set with
+    /// `FileSystemWatcherBuilder::max_queued_events` was reached.
+    pub dropped_events: u64,
+}
+
+impl Stats {
+    /// Returns the number of events of the specified type returned by the stream.
+    pub fn count(&self, kind: EventKind) -> u64 {
+        self.events.get(&kind).cloned().unwrap_or(0)
+    }
+}
+
+/// Selects how directories which cannot be read due to missing permissions are handled, see
+/// `FileSystemWatcherBuilder::unreadable_directories()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnreadableDirectoryPolicy {
+    /// The directory is silently skipped.
+    Skip,
+    /// A `WatchFailed` event is generated for the directory.
+    Report,
+    /// A `WatchFailed` event is generated and the directory is tried again after the delay, in
+    /// case the permissions have been changed in the meantime. Every failed attempt generates
+    /// another `WatchFailed` event. Requires the `delay` feature.
+    #[cfg(feature = "delay")]
+    Retry(Duration),
+}
+
+/// Selects what happens to events while the watcher is paused, see `FileSystemWatcher::pause()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PausePolicy {
+    /// The events are returned once the watcher is resumed.
+    Buffer,
+    /// The events are dropped, except for the ones which `EventKindSet` always passes through.
+    Discard,
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum StopReason {
     DirectoryRemoved,
+    /// The root directory was moved to a different location. Only reported on Linux, other
+    /// backends report such moves as `DirectoryRemoved`.
+    DirectoryMoved,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopReason::DirectoryRemoved => write!(f, "directory removed"),
+            StopReason::DirectoryMoved => write!(f, "directory moved"),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum Error {
     Io(std::io::Error),
+    /// The root directory does not exist.
+    NotFound(OsString),
+    /// The root is neither a directory nor, on Linux, a regular file.
+    NotADirectory(OsString),
+    /// An ignore pattern passed to `FileSystemWatcherBuilder::ignore` is not a valid glob
+    /// pattern.
+    InvalidPattern(String),
+    /// No inotify watch could be installed for the directory, because the per-user limit of
+    /// watches (`fs.inotify.max_user_watches`) or inotify instances has been reached. The
+    /// directory (and its subdirectories) are not monitored, whereas all other directories are
+    /// still being monitored. Raising the limit and calling `FileSystemWatcher::watch` for the
+    /// directory afterwards adds the missing watches.
+    WatchLimitExceeded(OsString),
+    /// The inotify instance could not be created, e.g., because the per-user limit of inotify
+    /// instances (`fs.inotify.max_user_instances`) has been reached. Raising the limit or closing
+    /// other watchers allows creating new watchers again.
+    InotifyInit(io::Error),
+    /// No watch could be installed for the directory at `path`.
+    AddWatch {
+        path: OsString,
+        source: io::Error,
+    },
+    /// The watch for the directory at `path` could not be removed.
+    RemoveWatch {
+        path: OsString,
+        source: io::Error,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::NotFound(path) => write!(f, "{} does not exist", path.to_string_lossy()),
+            Error::NotADirectory(path) => {
+                write!(f, "{} is not a directory", path.to_string_lossy())
+            }
+            Error::InvalidPattern(pattern) => write!(f, "invalid ignore pattern: {}", pattern),
+            Error::WatchLimitExceeded(path) => write!(
+                f,
+                "inotify watch limit exceeded, cannot watch {}",
+                path.to_string_lossy()
+            ),
+            Error::InotifyInit(e) => write!(f, "cannot create inotify instance: {}", e),
+            Error::AddWatch { path, source } => {
+                write!(f, "cannot watch {}: {}", path.to_string_lossy(), source)
+            }
+            Error::RemoveWatch { path, source } => write!(
+                f,
+                "cannot remove watch for {}: {}",
+                path.to_string_lossy(),
+                source
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) | Error::InotifyInit(e) => Some(e),
+            Error::AddWatch { source, .. } | Error::RemoveWatch { source, .. } => Some(source),
+            _ => None,
+        }
+    }
 }
 
 impl From<std::io::Error> for Error {
@@ -76,8 +1195,235 @@ impl From<std::io::Error> for Error {
 
 #[cfg(test)]
 mod tests {
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use tokio::time;
+
+    use super::{Error, EventKind, FileSystemEvent, FileSystemWatcher, StopReason};
+
+    /// Returns the first event which matches the predicate, or `None` if no such event is
+    /// received within a second.
+    async fn wait_for<F>(watcher: &mut FileSystemWatcher, predicate: F) -> Option<FileSystemEvent>
+    where
+        F: Fn(&FileSystemEvent) -> bool,
+    {
+        time::timeout(Duration::from_secs(1), watcher.wait_for(predicate))
+            .await
+            .unwrap_or(None)
+    }
+
+    #[tokio::test]
+    async fn file_creation_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher = FileSystemWatcher::new(dir.path().as_os_str()).unwrap();
+        // Watches are only installed while the stream is polled.
+        let watched = wait_for(&mut watcher, |event| {
+            event.kind() == EventKind::DirectoryWatched
+        })
+        .await;
+        assert!(watched.is_some());
+
+        let file = dir.path().join("a");
+        fs::write(&file, "a").unwrap();
+        let created = wait_for(&mut watcher, |event| match event {
+            FileSystemEvent::FileCreated(path, _) => *path == file,
+            _ => false,
+        })
+        .await;
+        assert!(created.is_some(), "no FileCreated event for {:?}", file);
+    }
+
+    #[tokio::test]
+    async fn available_events_are_returned_without_waiting() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher = FileSystemWatcher::new(dir.path().as_os_str()).unwrap();
+        let events = watcher.try_next_batch();
+        assert!(events
+            .iter()
+            .any(|event| event.kind() == EventKind::DirectoryWatched));
+
+        let file = dir.path().join("a");
+        fs::write(&file, "a").unwrap();
+        time::delay_for(Duration::from_millis(100)).await;
+        let events = watcher.try_next_batch();
+        assert!(events.iter().any(|event| match event {
+            FileSystemEvent::FileCreated(path, _) => *path == file,
+            _ => false,
+        }));
+        assert!(watcher.try_next_batch().is_empty());
+    }
+
+    #[test]
+    fn events_are_returned_without_a_runtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a");
+        let expected = file.clone();
+        let events = thread::spawn(move || {
+            let mut watcher = FileSystemWatcher::new(dir.path().as_os_str()).unwrap();
+            let mut events = watcher.try_next_batch();
+            fs::write(&file, "a").unwrap();
+            for _ in 0..100 {
+                thread::sleep(Duration::from_millis(10));
+                events.extend(watcher.try_next_batch());
+            }
+            events
+        })
+        .join()
+        .unwrap();
+        assert!(events
+            .iter()
+            .any(|event| event.kind() == EventKind::DirectoryWatched));
+        assert!(events.iter().any(|event| match event {
+            FileSystemEvent::FileCreated(path, _) => *path == expected,
+            _ => false,
+        }));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn snapshot_reports_the_tree_once() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("a")).unwrap();
+        fs::write(dir.path().join("a/file"), "").unwrap();
+        let watcher = FileSystemWatcher::snapshot(dir.path().as_os_str()).unwrap();
+        let events = time::timeout(Duration::from_secs(1), watcher.collect::<Vec<_>>())
+            .await
+            .unwrap();
+        assert_eq!(
+            format!("{:?}", events),
+            format!(
+                "{:?}",
+                vec![
+                    FileSystemEvent::DirectoryCreated(dir.path().join("a").into()),
+                    FileSystemEvent::FileCreated(dir.path().join("a/file").into(), None),
+                    FileSystemEvent::ScanComplete,
+                ]
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn removing_the_root_stops_the_watcher() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir(&root).unwrap();
+        let mut watcher = FileSystemWatcher::new(root.as_os_str()).unwrap();
+        wait_for(&mut watcher, |event| {
+            event.kind() == EventKind::DirectoryWatched
+        })
+        .await;
+
+        fs::remove_dir(&root).unwrap();
+        let stopped = wait_for(&mut watcher, |event| event.kind() == EventKind::Stopped).await;
+        match stopped {
+            Some(FileSystemEvent::Stopped(StopReason::DirectoryRemoved)) => {}
+            event => panic!("unexpected event: {:?}", event),
+        }
+        assert!(!watcher.is_active());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn new_scanned_only_reports_later_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        let mut watcher = FileSystemWatcher::new_scanned(dir.path().as_os_str())
+            .await
+            .unwrap();
+        assert_eq!(watcher.watched_paths().len(), 3);
+
+        fs::write(dir.path().join("a/b/file"), "a").unwrap();
+        let event = time::timeout(Duration::from_secs(1), watcher.next()).await;
+        assert_eq!(
+            format!("{:?}", event),
+            format!(
+                "{:?}",
+                Ok::<_, ()>(Some(FileSystemEvent::FileCreated(
+                    dir.path().join("a/b/file").into(),
+                    None
+                )))
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn events_are_sent_to_the_channel() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut events = FileSystemWatcher::new(dir.path().as_os_str())
+            .unwrap()
+            .into_channel();
+        let watched = time::timeout(Duration::from_secs(1), events.recv())
+            .await
+            .unwrap();
+        assert_eq!(
+            watched.map(|event| event.kind()),
+            Some(EventKind::DirectoryWatched)
+        );
+    }
+
+    #[test]
+    fn roots_can_be_canonicalized() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("a")).unwrap();
+        let root = dir.path().join("a/..");
+        let watcher = FileSystemWatcher::builder(root.as_os_str())
+            .canonicalize(true)
+            .build()
+            .unwrap();
+        let mut events = watcher.into_blocking_iter();
+        assert_eq!(
+            format!("{:?}", events.next()),
+            format!(
+                "{:?}",
+                Some(FileSystemEvent::DirectoryWatched(
+                    fs::canonicalize(dir.path()).unwrap().into()
+                ))
+            )
+        );
+
+        let missing = dir.path().join("missing");
+        let result = FileSystemWatcher::builder(missing.as_os_str())
+            .canonicalize(true)
+            .build();
+        match result {
+            Err(Error::NotFound(path)) => assert_eq!(path, missing),
+            _ => panic!("unexpected result"),
+        }
+    }
+
+    #[test]
+    fn watcher_is_send_and_sync() {
+        fn assert_send<T: Send>() {}
+        fn assert_sync<T: Sync>() {}
+        assert_send::<FileSystemWatcher>();
+        assert_sync::<FileSystemWatcher>();
+        assert_send::<FileSystemEvent>();
+    }
+
+    #[test]
+    fn blocking_iter_returns_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let watcher = FileSystemWatcher::new(dir.path().as_os_str()).unwrap();
+        let mut events = watcher.into_blocking_iter();
+        assert_eq!(
+            format!("{:?}", events.next()),
+            format!(
+                "{:?}",
+                Some(FileSystemEvent::DirectoryWatched(
+                    dir.path().as_os_str().to_owned()
+                ))
+            )
+        );
+    }
+
     #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
+    fn events_are_displayed_concisely() {
+        let created = FileSystemEvent::FileCreated("/foo/bar".into(), None);
+        assert_eq!(created.to_string(), "created file /foo/bar");
+        let moved = FileSystemEvent::FileMoved("/a".into(), "/b".into());
+        assert_eq!(moved.to_string(), "moved /a -> /b");
     }
 }
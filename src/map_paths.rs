@@ -0,0 +1,84 @@
+//! Stream adapter which transforms the paths of the events.
+
+use std::ffi::OsString;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::stream::Stream;
+
+use super::FileSystemEvent;
+
+/// A stream which applies a function to all paths of the events, see
+/// `FileSystemWatcher::map_paths()` and `FileSystemEvent::map_paths()`.
+pub struct MapPaths<T, F>
+where
+    T: Stream<Item = FileSystemEvent>,
+    F: FnMut(OsString) -> OsString,
+{
+    input: Pin<Box<T>>,
+    f: F,
+}
+
+impl<T, F> MapPaths<T, F>
+where
+    T: Stream<Item = FileSystemEvent>,
+    F: FnMut(OsString) -> OsString,
+{
+    pub fn new(input: T, f: F) -> Self {
+        Self {
+            input: Box::pin(input),
+            f,
+        }
+    }
+}
+
+impl<T, F> Stream for MapPaths<T, F>
+where
+    T: Stream<Item = FileSystemEvent>,
+    F: FnMut(OsString) -> OsString,
+{
+    type Item = FileSystemEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        // Safe, as we will not move self_.
+        let self_ = unsafe { self.get_unchecked_mut() };
+
+        let f = &mut self_.f;
+        Pin::as_mut(&mut self_.input)
+            .poll_next(cx)
+            .map(|event| event.map(|event| event.map_paths(f)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+    use std::path::Path;
+
+    use futures::stream::{self, StreamExt};
+
+    use super::MapPaths;
+    use crate::{FileSystemEvent, StopReason};
+
+    #[tokio::test]
+    async fn all_paths_are_mapped() {
+        let input = stream::iter(vec![
+            FileSystemEvent::FileMoved("/root/a".into(), "/root/b/c".into()),
+            FileSystemEvent::DirectoriesWatched(vec!["/root".into(), "/root/b".into()]),
+            FileSystemEvent::FileRemoved("/other/d".into()),
+            FileSystemEvent::Stopped(StopReason::DirectoryRemoved),
+        ]);
+        let events = MapPaths::new(input, |path: OsString| {
+            match Path::new(&path).strip_prefix("/root") {
+                Ok(relative) => relative.as_os_str().to_owned(),
+                Err(_) => path,
+            }
+        })
+        .collect::<Vec<_>>()
+        .await;
+        assert_eq!(
+            format!("{:?}", events),
+            r#"[FileMoved("a", "b/c"), DirectoriesWatched(["", "b"]), FileRemoved("/other/d"), Stopped(DirectoryRemoved)]"#
+        );
+    }
+}
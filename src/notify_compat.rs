@@ -0,0 +1,177 @@
+//! Conversion of the events into the event type of the `notify` crate.
+//!
+//! The variants are mapped onto the closest `notify::EventKind` category. Events without a
+//! counterpart (e.g., `DirectoryWatched` or `Stopped`) are converted into `EventKind::Other`
+//! events which carry the description of the original event as their info attribute.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::stream::Stream;
+use notify::event::{
+    AccessKind, AccessMode, CreateKind, DataChange, EventKind, Flag, MetadataKind, ModifyKind,
+    RemoveKind, RenameMode,
+};
+
+use super::FileSystemEvent;
+
+impl From<FileSystemEvent> for notify::Event {
+    fn from(event: FileSystemEvent) -> Self {
+        let kind = notify_kind(&event);
+        let mut result = notify::Event::new(kind);
+        let (first, second) = event.paths();
+        for path in first.into_iter().chain(second) {
+            result = result.add_path(path.to_owned());
+        }
+        if let FileSystemEvent::DirectoriesWatched(paths) = &event {
+            for path in paths {
+                result = result.add_path(path.into());
+            }
+        }
+        // notify uses the tracker to correlate the two halves of a rename.
+        if let Some(cookie) = event.cookie() {
+            result = result.set_tracker(cookie as usize);
+        }
+        match event {
+            FileSystemEvent::Overflow => result.set_flag(Flag::Rescan),
+            event if kind == EventKind::Other => result.set_info(&event.to_string()),
+            _ => result,
+        }
+    }
+}
+
+fn notify_kind(event: &FileSystemEvent) -> EventKind {
+    match event {
+        FileSystemEvent::DirectoryCreated(_) => EventKind::Create(CreateKind::Folder),
+        FileSystemEvent::FileCreated(_, _) => EventKind::Create(CreateKind::File),
+        FileSystemEvent::FileModified(_, _) => EventKind::Modify(ModifyKind::Data(DataChange::Any)),
+        FileSystemEvent::DirectoryModified(_) | FileSystemEvent::FileAttributesChanged(_) => {
+            EventKind::Modify(ModifyKind::Metadata(MetadataKind::Any))
+        }
+        FileSystemEvent::DirectoryMoved(_, _) | FileSystemEvent::FileMoved(_, _) => {
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both))
+        }
+        FileSystemEvent::MovedFrom { .. } => EventKind::Modify(ModifyKind::Name(RenameMode::From)),
+        FileSystemEvent::MovedTo { .. } => EventKind::Modify(ModifyKind::Name(RenameMode::To)),
+        FileSystemEvent::DirectoryRemoved(_) => EventKind::Remove(RemoveKind::Folder),
+        FileSystemEvent::FileRemoved(_) => EventKind::Remove(RemoveKind::File),
+        FileSystemEvent::FileOpened(_) => EventKind::Access(AccessKind::Open(AccessMode::Any)),
+        FileSystemEvent::FileAccessed(_) => EventKind::Access(AccessKind::Read),
+        FileSystemEvent::FileWriteClosed(_) => {
+            EventKind::Access(AccessKind::Close(AccessMode::Write))
+        }
+        FileSystemEvent::FileClosed(_) => EventKind::Access(AccessKind::Close(AccessMode::Read)),
+        FileSystemEvent::Stopped(_)
+        | FileSystemEvent::RootStopped(_, _)
+        | FileSystemEvent::DirectoryWatched(_)
+        | FileSystemEvent::DirectoriesWatched(_)
+        | FileSystemEvent::FileWatched(_)
+        | FileSystemEvent::ScanComplete
+        | FileSystemEvent::Overflow
+        | FileSystemEvent::Raw { .. }
+        | FileSystemEvent::WatchFailed(_, _)
+        | FileSystemEvent::WatchLimitWarning(_)
+        | FileSystemEvent::NonUtf8Path(_, _)
+        | FileSystemEvent::Error(_) => EventKind::Other,
+    }
+}
+
+/// A stream which converts the events into the results passed to `notify` event handlers.
+///
+/// `Error` and `WatchFailed` events are returned as errors, all other events are converted via
+/// `From<FileSystemEvent> for notify::Event`. See `FileSystemWatcher::into_notify_events()`.
+pub struct NotifyEvents<T>
+where
+    T: Stream<Item = FileSystemEvent>,
+{
+    input: Pin<Box<T>>,
+}
+
+impl<T> NotifyEvents<T>
+where
+    T: Stream<Item = FileSystemEvent>,
+{
+    pub fn new(input: T) -> Self {
+        Self {
+            input: Box::pin(input),
+        }
+    }
+}
+
+impl<T> Stream for NotifyEvents<T>
+where
+    T: Stream<Item = FileSystemEvent>,
+{
+    type Item = notify::Result<notify::Event>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        // Safe, as we will not move self_.
+        let self_ = unsafe { self.get_unchecked_mut() };
+
+        Pin::as_mut(&mut self_.input)
+            .poll_next(cx)
+            .map(|event| event.map(to_result))
+    }
+}
+
+fn to_result(event: FileSystemEvent) -> notify::Result<notify::Event> {
+    match event {
+        FileSystemEvent::Error(error) => Err(notify::Error::generic(&error.to_string())),
+        FileSystemEvent::WatchFailed(path, error) => {
+            Err(notify::Error::generic(&error.to_string()).add_path(path.into()))
+        }
+        event => Ok(event.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use futures::stream::{self, StreamExt};
+    use notify::event::{CreateKind, EventKind, ModifyKind, RenameMode};
+
+    use super::NotifyEvents;
+    use crate::{Error, FileSystemEvent};
+
+    #[test]
+    fn events_are_converted() {
+        let created: notify::Event = FileSystemEvent::DirectoryCreated("/a".into()).into();
+        assert_eq!(created.kind, EventKind::Create(CreateKind::Folder));
+        assert_eq!(created.paths, vec![PathBuf::from("/a")]);
+
+        let moved: notify::Event = FileSystemEvent::FileMoved("/a".into(), "/b".into()).into();
+        assert_eq!(
+            moved.kind,
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both))
+        );
+        assert_eq!(moved.paths, vec![PathBuf::from("/a"), PathBuf::from("/b")]);
+
+        let moved_to: notify::Event = FileSystemEvent::MovedTo {
+            path: "/c".into(),
+            cookie: 42,
+            is_dir: false,
+        }
+        .into();
+        assert_eq!(
+            moved_to.kind,
+            EventKind::Modify(ModifyKind::Name(RenameMode::To))
+        );
+        assert_eq!(moved_to.tracker(), Some(42));
+
+        let watched: notify::Event = FileSystemEvent::DirectoryWatched("/d".into()).into();
+        assert_eq!(watched.kind, EventKind::Other);
+        assert_eq!(watched.info(), Some("watching directory /d"));
+    }
+
+    #[tokio::test]
+    async fn errors_are_returned_as_errors() {
+        let input = stream::iter(vec![
+            FileSystemEvent::FileCreated("/a".into(), None),
+            FileSystemEvent::Error(Error::NotFound("/b".into())),
+        ]);
+        let results = NotifyEvents::new(input).collect::<Vec<_>>().await;
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+    }
+}
@@ -0,0 +1,355 @@
+//! Serde support for the event types.
+//!
+//! `OsString` and `io::Error` cannot be serialized in a platform-independent way, so the events
+//! are converted into mirror types which store paths as UTF-8 strings and errors as their
+//! message. Only `NonUtf8Path` events keep the raw bytes of their path, as the path cannot be
+//! represented as a string without losing information.
+
+use std::ffi::OsString;
+use std::io;
+#[cfg(unix)]
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::{Error, EventKind, FileMetadata, FileSystemEvent, StopReason};
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "FileSystemEvent")]
+enum SerializedEvent {
+    Stopped(StopReason),
+    RootStopped(String, StopReason),
+    DirectoryWatched(String),
+    DirectoriesWatched(Vec<String>),
+    FileWatched(String),
+    ScanComplete,
+    DirectoryCreated(String),
+    DirectoryModified(String),
+    DirectoryRemoved(String),
+    DirectoryMoved(String, String),
+    FileCreated(String, Option<FileMetadata>),
+    FileModified(String, Option<FileMetadata>),
+    FileAttributesChanged(String),
+    FileWriteClosed(String),
+    FileOpened(String),
+    FileAccessed(String),
+    FileClosed(String),
+    FileRemoved(String),
+    FileMoved(String, String),
+    MovedFrom {
+        path: String,
+        cookie: u32,
+        is_dir: bool,
+    },
+    MovedTo {
+        path: String,
+        cookie: u32,
+        is_dir: bool,
+    },
+    Overflow,
+    Raw {
+        path: String,
+        mask: u32,
+        cookie: u32,
+    },
+    WatchFailed(String, SerializedError),
+    WatchLimitWarning(usize),
+    NonUtf8Path(Vec<u8>, EventKind),
+    Error(SerializedError),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "Error")]
+enum SerializedError {
+    Io(String),
+    NotFound(String),
+    NotADirectory(String),
+    InvalidPattern(String),
+    WatchLimitExceeded(String),
+    InotifyInit(String),
+    AddWatch { path: String, source: String },
+    RemoveWatch { path: String, source: String },
+}
+
+fn to_string(path: &OsString) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+#[cfg(unix)]
+fn to_bytes(path: &OsString) -> Vec<u8> {
+    path.as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+fn to_bytes(path: &OsString) -> Vec<u8> {
+    // Paths are only reported as non-UTF-8 on Linux.
+    to_string(path).into_bytes()
+}
+
+#[cfg(unix)]
+fn from_bytes(bytes: Vec<u8>) -> OsString {
+    OsString::from_vec(bytes)
+}
+
+#[cfg(not(unix))]
+fn from_bytes(bytes: Vec<u8>) -> OsString {
+    String::from_utf8_lossy(&bytes).into_owned().into()
+}
+
+fn io_error(message: String) -> io::Error {
+    // The error kind is lost, only the message is preserved.
+    io::Error::new(io::ErrorKind::Other, message)
+}
+
+impl From<&FileSystemEvent> for SerializedEvent {
+    fn from(event: &FileSystemEvent) -> Self {
+        match event {
+            FileSystemEvent::Stopped(reason) => SerializedEvent::Stopped(reason.clone()),
+            FileSystemEvent::RootStopped(path, reason) => {
+                SerializedEvent::RootStopped(to_string(path), reason.clone())
+            }
+            FileSystemEvent::DirectoryWatched(path) => {
+                SerializedEvent::DirectoryWatched(to_string(path))
+            }
+            FileSystemEvent::DirectoriesWatched(paths) => {
+                SerializedEvent::DirectoriesWatched(paths.iter().map(to_string).collect())
+            }
+            FileSystemEvent::FileWatched(path) => SerializedEvent::FileWatched(to_string(path)),
+            FileSystemEvent::ScanComplete => SerializedEvent::ScanComplete,
+            FileSystemEvent::DirectoryCreated(path) => {
+                SerializedEvent::DirectoryCreated(to_string(path))
+            }
+            FileSystemEvent::DirectoryModified(path) => {
+                SerializedEvent::DirectoryModified(to_string(path))
+            }
+            FileSystemEvent::DirectoryRemoved(path) => {
+                SerializedEvent::DirectoryRemoved(to_string(path))
+            }
+            FileSystemEvent::DirectoryMoved(from, to) => {
+                SerializedEvent::DirectoryMoved(to_string(from), to_string(to))
+            }
+            FileSystemEvent::FileCreated(path, metadata) => {
+                SerializedEvent::FileCreated(to_string(path), *metadata)
+            }
+            FileSystemEvent::FileModified(path, metadata) => {
+                SerializedEvent::FileModified(to_string(path), *metadata)
+            }
+            FileSystemEvent::FileAttributesChanged(path) => {
+                SerializedEvent::FileAttributesChanged(to_string(path))
+            }
+            FileSystemEvent::FileWriteClosed(path) => {
+                SerializedEvent::FileWriteClosed(to_string(path))
+            }
+            FileSystemEvent::FileOpened(path) => SerializedEvent::FileOpened(to_string(path)),
+            FileSystemEvent::FileAccessed(path) => SerializedEvent::FileAccessed(to_string(path)),
+            FileSystemEvent::FileClosed(path) => SerializedEvent::FileClosed(to_string(path)),
+            FileSystemEvent::FileRemoved(path) => SerializedEvent::FileRemoved(to_string(path)),
+            FileSystemEvent::FileMoved(from, to) => {
+                SerializedEvent::FileMoved(to_string(from), to_string(to))
+            }
+            FileSystemEvent::MovedFrom {
+                path,
+                cookie,
+                is_dir,
+            } => SerializedEvent::MovedFrom {
+                path: to_string(path),
+                cookie: *cookie,
+                is_dir: *is_dir,
+            },
+            FileSystemEvent::MovedTo {
+                path,
+                cookie,
+                is_dir,
+            } => SerializedEvent::MovedTo {
+                path: to_string(path),
+                cookie: *cookie,
+                is_dir: *is_dir,
+            },
+            FileSystemEvent::Overflow => SerializedEvent::Overflow,
+            FileSystemEvent::Raw { path, mask, cookie } => SerializedEvent::Raw {
+                path: to_string(path),
+                mask: *mask,
+                cookie: *cookie,
+            },
+            FileSystemEvent::WatchFailed(path, error) => {
+                SerializedEvent::WatchFailed(to_string(path), error.into())
+            }
+            FileSystemEvent::WatchLimitWarning(count) => SerializedEvent::WatchLimitWarning(*count),
+            FileSystemEvent::NonUtf8Path(path, kind) => {
+                SerializedEvent::NonUtf8Path(to_bytes(path), *kind)
+            }
+            FileSystemEvent::Error(error) => SerializedEvent::Error(error.into()),
+        }
+    }
+}
+
+impl From<SerializedEvent> for FileSystemEvent {
+    fn from(event: SerializedEvent) -> Self {
+        match event {
+            SerializedEvent::Stopped(reason) => FileSystemEvent::Stopped(reason),
+            SerializedEvent::RootStopped(path, reason) => {
+                FileSystemEvent::RootStopped(path.into(), reason)
+            }
+            SerializedEvent::DirectoryWatched(path) => {
+                FileSystemEvent::DirectoryWatched(path.into())
+            }
+            SerializedEvent::DirectoriesWatched(paths) => {
+                FileSystemEvent::DirectoriesWatched(paths.into_iter().map(OsString::from).collect())
+            }
+            SerializedEvent::FileWatched(path) => FileSystemEvent::FileWatched(path.into()),
+            SerializedEvent::ScanComplete => FileSystemEvent::ScanComplete,
+            SerializedEvent::DirectoryCreated(path) => {
+                FileSystemEvent::DirectoryCreated(path.into())
+            }
+            SerializedEvent::DirectoryModified(path) => {
+                FileSystemEvent::DirectoryModified(path.into())
+            }
+            SerializedEvent::DirectoryRemoved(path) => {
+                FileSystemEvent::DirectoryRemoved(path.into())
+            }
+            SerializedEvent::DirectoryMoved(from, to) => {
+                FileSystemEvent::DirectoryMoved(from.into(), to.into())
+            }
+            SerializedEvent::FileCreated(path, metadata) => {
+                FileSystemEvent::FileCreated(path.into(), metadata)
+            }
+            SerializedEvent::FileModified(path, metadata) => {
+                FileSystemEvent::FileModified(path.into(), metadata)
+            }
+            SerializedEvent::FileAttributesChanged(path) => {
+                FileSystemEvent::FileAttributesChanged(path.into())
+            }
+            SerializedEvent::FileWriteClosed(path) => FileSystemEvent::FileWriteClosed(path.into()),
+            SerializedEvent::FileOpened(path) => FileSystemEvent::FileOpened(path.into()),
+            SerializedEvent::FileAccessed(path) => FileSystemEvent::FileAccessed(path.into()),
+            SerializedEvent::FileClosed(path) => FileSystemEvent::FileClosed(path.into()),
+            SerializedEvent::FileRemoved(path) => FileSystemEvent::FileRemoved(path.into()),
+            SerializedEvent::FileMoved(from, to) => {
+                FileSystemEvent::FileMoved(from.into(), to.into())
+            }
+            SerializedEvent::MovedFrom {
+                path,
+                cookie,
+                is_dir,
+            } => FileSystemEvent::MovedFrom {
+                path: path.into(),
+                cookie,
+                is_dir,
+            },
+            SerializedEvent::MovedTo {
+                path,
+                cookie,
+                is_dir,
+            } => FileSystemEvent::MovedTo {
+                path: path.into(),
+                cookie,
+                is_dir,
+            },
+            SerializedEvent::Overflow => FileSystemEvent::Overflow,
+            SerializedEvent::Raw { path, mask, cookie } => FileSystemEvent::Raw {
+                path: path.into(),
+                mask,
+                cookie,
+            },
+            SerializedEvent::WatchFailed(path, error) => {
+                FileSystemEvent::WatchFailed(path.into(), error.into())
+            }
+            SerializedEvent::WatchLimitWarning(count) => FileSystemEvent::WatchLimitWarning(count),
+            SerializedEvent::NonUtf8Path(path, kind) => {
+                FileSystemEvent::NonUtf8Path(from_bytes(path), kind)
+            }
+            SerializedEvent::Error(error) => FileSystemEvent::Error(error.into()),
+        }
+    }
+}
+
+impl From<&Error> for SerializedError {
+    fn from(error: &Error) -> Self {
+        match error {
+            Error::Io(e) => SerializedError::Io(e.to_string()),
+            Error::NotFound(path) => SerializedError::NotFound(to_string(path)),
+            Error::NotADirectory(path) => SerializedError::NotADirectory(to_string(path)),
+            Error::InvalidPattern(pattern) => SerializedError::InvalidPattern(pattern.clone()),
+            Error::WatchLimitExceeded(path) => SerializedError::WatchLimitExceeded(to_string(path)),
+            Error::InotifyInit(e) => SerializedError::InotifyInit(e.to_string()),
+            Error::AddWatch { path, source } => SerializedError::AddWatch {
+                path: to_string(path),
+                source: source.to_string(),
+            },
+            Error::RemoveWatch { path, source } => SerializedError::RemoveWatch {
+                path: to_string(path),
+                source: source.to_string(),
+            },
+        }
+    }
+}
+
+impl From<SerializedError> for Error {
+    fn from(error: SerializedError) -> Self {
+        match error {
+            SerializedError::Io(message) => Error::Io(io_error(message)),
+            SerializedError::NotFound(path) => Error::NotFound(path.into()),
+            SerializedError::NotADirectory(path) => Error::NotADirectory(path.into()),
+            SerializedError::InvalidPattern(pattern) => Error::InvalidPattern(pattern),
+            SerializedError::WatchLimitExceeded(path) => Error::WatchLimitExceeded(path.into()),
+            SerializedError::InotifyInit(message) => Error::InotifyInit(io_error(message)),
+            SerializedError::AddWatch { path, source } => Error::AddWatch {
+                path: path.into(),
+                source: io_error(source),
+            },
+            SerializedError::RemoveWatch { path, source } => Error::RemoveWatch {
+                path: path.into(),
+                source: io_error(source),
+            },
+        }
+    }
+}
+
+impl Serialize for FileSystemEvent {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SerializedEvent::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for FileSystemEvent {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        SerializedEvent::deserialize(deserializer).map(FileSystemEvent::from)
+    }
+}
+
+impl Serialize for Error {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SerializedError::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Error {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        SerializedError::deserialize(deserializer).map(Error::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Error, FileSystemEvent};
+
+    #[test]
+    fn events_round_trip() {
+        let events = vec![
+            FileSystemEvent::FileMoved("/a".into(), "/b".into()),
+            FileSystemEvent::MovedTo {
+                path: "/c".into(),
+                cookie: 42,
+                is_dir: true,
+            },
+            FileSystemEvent::WatchFailed("/d".into(), Error::InvalidPattern("[".to_owned())),
+        ];
+        let json = serde_json::to_string(&events).unwrap();
+        assert_eq!(
+            json,
+            r#"[{"FileMoved":["/a","/b"]},{"MovedTo":{"path":"/c","cookie":42,"is_dir":true}},{"WatchFailed":["/d",{"InvalidPattern":"["}]}]"#
+        );
+        let deserialized: Vec<FileSystemEvent> = serde_json::from_str(&json).unwrap();
+        assert_eq!(format!("{:?}", deserialized), format!("{:?}", events));
+    }
+}
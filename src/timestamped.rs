@@ -0,0 +1,100 @@
+//! Stream adapter which records when each event was received.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime};
+
+use futures::stream::Stream;
+
+use super::FileSystemEvent;
+
+/// An event together with the time at which it was returned by the underlying stream.
+///
+/// inotify does not provide timestamps, so the time is recorded when the event is read and not
+/// when the change happened. If the events are delayed by a `FileEventDelay`, the time refers to
+/// the moment the delayed event was returned.
+#[derive(Debug)]
+pub struct Timed<E> {
+    pub event: E,
+    /// Wall-clock time, e.g., for audit logs.
+    pub time: SystemTime,
+    /// Monotonic time, e.g., to measure the time between two events.
+    pub instant: Instant,
+}
+
+impl<E> Timed<E> {
+    /// Records the current time for the event.
+    pub fn now(event: E) -> Self {
+        Self {
+            event,
+            time: SystemTime::now(),
+            instant: Instant::now(),
+        }
+    }
+
+    /// Returns the event without the timestamp.
+    pub fn into_inner(self) -> E {
+        self.event
+    }
+}
+
+/// A stream which attaches the current time to every event, see
+/// `FileSystemWatcher::with_timestamps()`.
+pub struct Timestamped<T>
+where
+    T: Stream<Item = FileSystemEvent>,
+{
+    input: Pin<Box<T>>,
+}
+
+impl<T> Timestamped<T>
+where
+    T: Stream<Item = FileSystemEvent>,
+{
+    pub fn new(input: T) -> Self {
+        Self {
+            input: Box::pin(input),
+        }
+    }
+}
+
+impl<T> Stream for Timestamped<T>
+where
+    T: Stream<Item = FileSystemEvent>,
+{
+    type Item = Timed<FileSystemEvent>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        // Safe, as we will not move self_.
+        let self_ = unsafe { self.get_unchecked_mut() };
+
+        Pin::as_mut(&mut self_.input)
+            .poll_next(cx)
+            .map(|event| event.map(Timed::now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Instant, SystemTime};
+
+    use futures::stream::{self, StreamExt};
+
+    use super::Timestamped;
+    use crate::FileSystemEvent;
+
+    #[tokio::test]
+    async fn events_are_stamped_when_they_are_returned() {
+        let input = stream::iter(vec![
+            FileSystemEvent::FileCreated("/a".into(), None),
+            FileSystemEvent::FileRemoved("/a".into()),
+        ]);
+        let start = (SystemTime::now(), Instant::now());
+        let events = Timestamped::new(input).collect::<Vec<_>>().await;
+        assert_eq!(events.len(), 2);
+        assert!(events[0].time >= start.0);
+        assert!(events[0].instant >= start.1);
+        assert!(events[1].instant >= events[0].instant);
+        assert_eq!(format!("{:?}", events[1].event), r#"FileRemoved("/a")"#);
+    }
+}
@@ -0,0 +1,423 @@
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread::{self, JoinHandle};
+
+use futures::Stream;
+use log::warn;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use winapi::shared::minwindef::{DWORD, FALSE, LPVOID, TRUE};
+use winapi::um::fileapi::{CreateFileW, OPEN_EXISTING};
+use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+use winapi::um::ioapiset::{CancelIoEx, GetOverlappedResult};
+use winapi::um::minwinbase::OVERLAPPED;
+use winapi::um::synchapi::{CreateEventW, SetEvent, WaitForMultipleObjects};
+use winapi::um::winbase::{
+    ReadDirectoryChangesW, FILE_FLAG_BACKUP_SEMANTICS, FILE_FLAG_OVERLAPPED, INFINITE,
+    WAIT_OBJECT_0,
+};
+use winapi::um::winnt::{
+    FILE_ACTION_ADDED, FILE_ACTION_MODIFIED, FILE_ACTION_REMOVED, FILE_ACTION_RENAMED_NEW_NAME,
+    FILE_ACTION_RENAMED_OLD_NAME, FILE_LIST_DIRECTORY, FILE_NOTIFY_CHANGE_ATTRIBUTES,
+    FILE_NOTIFY_CHANGE_DIR_NAME, FILE_NOTIFY_CHANGE_FILE_NAME, FILE_NOTIFY_CHANGE_LAST_WRITE,
+    FILE_NOTIFY_INFORMATION, FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE, HANDLE,
+};
+
+use super::{FileSystemEvent, StopReason};
+use crate::directory_tracker::DirectoryTracker;
+
+/// Size of the buffer passed to ReadDirectoryChangesW, in DWORDs (the buffer has to be
+/// DWORD-aligned).
+const BUFFER_SIZE: usize = 16384;
+
+pub struct FileSystemWatcherWindows {
+    directories: DirectoryTracker,
+    events: UnboundedReceiver<FileSystemEvent>,
+    /// Manual-reset event which is signalled to stop the reader threads, stored as an integer so
+    /// that the watcher stays `Send`.
+    stop_event: usize,
+    /// One reader thread per root directory.
+    threads: Vec<JoinHandle<()>>,
+    closed: bool,
+}
+
+impl FileSystemWatcherWindows {
+    pub fn new(roots: &[OsString]) -> Result<FileSystemWatcherWindows, super::Error> {
+        // Open all directories first, so that we do not have to stop any threads on failure.
+        let mut handles = Vec::new();
+        for root in roots {
+            match open_directory(root) {
+                Ok(handle) => handles.push(handle),
+                Err(e) => {
+                    close_handles(&handles);
+                    return Err(e.into());
+                }
+            }
+        }
+        let stop_event = unsafe { CreateEventW(ptr::null_mut(), TRUE, FALSE, ptr::null()) };
+        if stop_event.is_null() {
+            let e = io::Error::last_os_error();
+            close_handles(&handles);
+            return Err(e.into());
+        }
+
+        let (sender, events) = mpsc::unbounded_channel();
+        let active_roots = Arc::new(AtomicUsize::new(roots.len()));
+        let mut threads = Vec::new();
+        for (root, handle) in roots.iter().zip(handles.into_iter()) {
+            let mut reader = DirectoryReader {
+                root_dir: PathBuf::from(root),
+                // The handle is passed as an integer, as raw pointers are not Send.
+                handle: handle as usize,
+                sender: sender.clone(),
+                stop_event: stop_event as usize,
+                active_roots: active_roots.clone(),
+                directories: HashSet::new(),
+                rename_from: None,
+            };
+            threads.push(thread::spawn(move || reader.run()));
+        }
+
+        Ok(FileSystemWatcherWindows {
+            directories: DirectoryTracker::new(roots),
+            events,
+            stop_event: stop_event as usize,
+            threads,
+            closed: false,
+        })
+    }
+
+    /// Stops reading changes. Afterwards, the stream does not return any further events.
+    pub fn close(&mut self) {
+        if self.closed {
+            return;
+        }
+        // The reader threads wait for either a change or the stop event. The event stays
+        // signalled, so threads which are currently processing changes exit before their next
+        // read. The threads cancel their pending read and close their directory handle.
+        unsafe {
+            SetEvent(self.stop_event as HANDLE);
+        }
+        for thread in self.threads.drain(..) {
+            thread.join().ok();
+        }
+        unsafe {
+            CloseHandle(self.stop_event as HANDLE);
+        }
+        self.closed = true;
+        self.directories.clear();
+    }
+
+    /// Returns the directories which are currently being monitored.
+    pub fn watched_paths(&self) -> Vec<OsString> {
+        self.directories.watched_paths()
+    }
+}
+
+impl Stream for FileSystemWatcherWindows {
+    type Item = FileSystemEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        // Safe, as we will not move self_.
+        let self_ = unsafe { self.get_unchecked_mut() };
+
+        if self_.closed {
+            // The watcher has been closed.
+            return Poll::Ready(None);
+        }
+
+        match self_.events.poll_recv(cx) {
+            Poll::Ready(Some(event)) => {
+                self_.directories.handle_event(&event);
+                return Poll::Ready(Some(event));
+            }
+            Poll::Ready(None) => return Poll::Ready(None),
+            Poll::Pending => {}
+        }
+
+        match self_.directories.next_watched() {
+            Some(event) => Poll::Ready(Some(event)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for FileSystemWatcherWindows {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+fn open_directory(path: &OsStr) -> io::Result<HANDLE> {
+    let wide_path = path.encode_wide().chain(Some(0)).collect::<Vec<_>>();
+    let handle = unsafe {
+        CreateFileW(
+            wide_path.as_ptr(),
+            FILE_LIST_DIRECTORY,
+            FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE,
+            ptr::null_mut(),
+            OPEN_EXISTING,
+            FILE_FLAG_BACKUP_SEMANTICS | FILE_FLAG_OVERLAPPED,
+            ptr::null_mut(),
+        )
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(handle)
+    }
+}
+
+fn close_handles(handles: &[HANDLE]) {
+    for handle in handles {
+        unsafe {
+            CloseHandle(*handle);
+        }
+    }
+}
+
+/// Result of a single ReadDirectoryChangesW call.
+enum ReadResult {
+    /// The number of bytes written into the buffer, zero if the buffer overflowed.
+    Changes(usize),
+    /// The watcher was closed.
+    Stopped,
+    Error(io::Error),
+}
+
+/// State of the thread which reads the changes from the directory handle.
+struct DirectoryReader {
+    root_dir: PathBuf,
+    handle: usize,
+    sender: UnboundedSender<FileSystemEvent>,
+    stop_event: usize,
+    /// Number of reader threads whose root directory has not been removed yet.
+    active_roots: Arc<AtomicUsize>,
+    /// Directories within the watched tree. Windows does not tell us whether a removed entry was
+    /// a directory, so we have to remember.
+    directories: HashSet<PathBuf>,
+    /// Old name of an entry which is being renamed, until the corresponding
+    /// FILE_ACTION_RENAMED_NEW_NAME arrives.
+    rename_from: Option<PathBuf>,
+}
+
+impl DirectoryReader {
+    fn run(&mut self) {
+        let root_dir = self.root_dir.clone();
+        self.add_directories(&root_dir);
+
+        // Auto-reset event which is signalled when a read has completed.
+        let read_event = unsafe { CreateEventW(ptr::null_mut(), FALSE, FALSE, ptr::null()) };
+        if read_event.is_null() {
+            self.send(FileSystemEvent::Error(io::Error::last_os_error().into()));
+            unsafe {
+                CloseHandle(self.handle as HANDLE);
+            }
+            return;
+        }
+
+        let mut buffer = vec![0u32; BUFFER_SIZE];
+        loop {
+            match self.read_changes(&mut buffer, read_event) {
+                ReadResult::Stopped => break,
+                ReadResult::Error(e) => {
+                    // Most likely, the root directory was removed.
+                    if !self.root_dir.is_dir() {
+                        if self.active_roots.fetch_sub(1, Ordering::SeqCst) == 1 {
+                            self.send(FileSystemEvent::Stopped(StopReason::DirectoryRemoved));
+                        } else {
+                            self.send(FileSystemEvent::RootStopped(
+                                self.root_dir.clone().into_os_string(),
+                                StopReason::DirectoryRemoved,
+                            ));
+                        }
+                    } else {
+                        self.send(FileSystemEvent::Error(e.into()));
+                    }
+                    break;
+                }
+                ReadResult::Changes(0) => {
+                    // The buffer overflowed and the changes were lost.
+                    warn!(
+                        "Change buffer overflow for {}",
+                        self.root_dir.to_string_lossy()
+                    );
+                    self.send(FileSystemEvent::Overflow);
+                }
+                ReadResult::Changes(_) => {
+                    if !self.process_buffer(&buffer) {
+                        // The receiver was dropped.
+                        break;
+                    }
+                }
+            }
+        }
+
+        unsafe {
+            CloseHandle(read_event);
+            CloseHandle(self.handle as HANDLE);
+        }
+    }
+
+    /// Starts an overlapped read and waits until it completes or until the watcher is closed.
+    fn read_changes(&self, buffer: &mut [u32], read_event: HANDLE) -> ReadResult {
+        let handle = self.handle as HANDLE;
+        let mut bytes_returned: DWORD = 0;
+        unsafe {
+            let mut overlapped: OVERLAPPED = mem::zeroed();
+            overlapped.hEvent = read_event;
+            let result = ReadDirectoryChangesW(
+                handle,
+                buffer.as_mut_ptr() as LPVOID,
+                (buffer.len() * mem::size_of::<u32>()) as DWORD,
+                TRUE,
+                FILE_NOTIFY_CHANGE_FILE_NAME
+                    | FILE_NOTIFY_CHANGE_DIR_NAME
+                    | FILE_NOTIFY_CHANGE_LAST_WRITE
+                    | FILE_NOTIFY_CHANGE_ATTRIBUTES,
+                ptr::null_mut(),
+                &mut overlapped,
+                None,
+            );
+            if result == 0 {
+                return ReadResult::Error(io::Error::last_os_error());
+            }
+
+            let wait_handles = [read_event, self.stop_event as HANDLE];
+            let wait_result = WaitForMultipleObjects(2, wait_handles.as_ptr(), FALSE, INFINITE);
+            if wait_result != WAIT_OBJECT_0 {
+                // The watcher was closed. The kernel writes into the buffer and the OVERLAPPED
+                // structure until the cancelled read has completed, so we have to wait for it.
+                CancelIoEx(handle, &mut overlapped);
+                GetOverlappedResult(handle, &mut overlapped, &mut bytes_returned, TRUE);
+                return ReadResult::Stopped;
+            }
+            if GetOverlappedResult(handle, &mut overlapped, &mut bytes_returned, FALSE) == 0 {
+                return ReadResult::Error(io::Error::last_os_error());
+            }
+        }
+        ReadResult::Changes(bytes_returned as usize)
+    }
+
+    /// Translates all entries in the buffer, returns false if the receiver has been dropped.
+    fn process_buffer(&mut self, buffer: &[u32]) -> bool {
+        let mut offset = 0;
+        loop {
+            let (action, name, next_offset) = unsafe {
+                let info =
+                    (buffer.as_ptr() as *const u8).add(offset) as *const FILE_NOTIFY_INFORMATION;
+                let name = slice::from_raw_parts(
+                    (*info).FileName.as_ptr(),
+                    (*info).FileNameLength as usize / mem::size_of::<u16>(),
+                );
+                (
+                    (*info).Action,
+                    OsString::from_wide(name),
+                    (*info).NextEntryOffset as usize,
+                )
+            };
+            let path = self.root_dir.join(name);
+            if let Some(event) = self.translate_action(action, path) {
+                if !self.send(event) {
+                    return false;
+                }
+            }
+            if next_offset == 0 {
+                return true;
+            }
+            offset += next_offset;
+        }
+    }
+
+    fn translate_action(&mut self, action: DWORD, path: PathBuf) -> Option<FileSystemEvent> {
+        match action {
+            FILE_ACTION_ADDED => {
+                if path.is_dir() {
+                    self.add_directories(&path);
+                    Some(FileSystemEvent::DirectoryCreated(path.into_os_string()))
+                } else {
+                    Some(FileSystemEvent::FileCreated(path.into_os_string(), None))
+                }
+            }
+            FILE_ACTION_REMOVED => {
+                if self.remove_directories(&path) {
+                    Some(FileSystemEvent::DirectoryRemoved(path.into_os_string()))
+                } else {
+                    Some(FileSystemEvent::FileRemoved(path.into_os_string()))
+                }
+            }
+            FILE_ACTION_MODIFIED => {
+                if self.directories.contains(&path) {
+                    Some(FileSystemEvent::DirectoryModified(path.into_os_string()))
+                } else {
+                    Some(FileSystemEvent::FileModified(path.into_os_string(), None))
+                }
+            }
+            FILE_ACTION_RENAMED_OLD_NAME => {
+                self.rename_from = Some(path);
+                None
+            }
+            FILE_ACTION_RENAMED_NEW_NAME => match self.rename_from.take() {
+                Some(from) => {
+                    if self.remove_directories(&from) {
+                        self.add_directories(&path);
+                        Some(FileSystemEvent::DirectoryMoved(
+                            from.into_os_string(),
+                            path.into_os_string(),
+                        ))
+                    } else {
+                        Some(FileSystemEvent::FileMoved(
+                            from.into_os_string(),
+                            path.into_os_string(),
+                        ))
+                    }
+                }
+                None => self.translate_action(FILE_ACTION_ADDED, path),
+            },
+            _ => {
+                warn!(
+                    "Unexpected change notification: {}, {}",
+                    path.to_string_lossy(),
+                    action
+                );
+                None
+            }
+        }
+    }
+
+    /// Registers a directory and all its subdirectories as known directories.
+    fn add_directories(&mut self, path: &Path) {
+        if let Ok(entries) = fs::read_dir(path) {
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                    self.add_directories(&entry.path());
+                }
+            }
+        }
+        self.directories.insert(path.to_owned());
+    }
+
+    /// Removes a directory and all its subdirectories from the known directories. Returns false
+    /// if the path was not a known directory.
+    fn remove_directories(&mut self, path: &Path) -> bool {
+        if !self.directories.contains(path) {
+            return false;
+        }
+        self.directories.retain(|p| !p.starts_with(path));
+        true
+    }
+
+    fn send(&self, event: FileSystemEvent) -> bool {
+        self.sender.send(event).is_ok()
+    }
+}
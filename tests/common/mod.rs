@@ -0,0 +1,94 @@
+//! Helpers shared by the integration tests.
+
+#![allow(dead_code)]
+
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use futures::stream::{Stream, StreamExt};
+use tempfile::TempDir;
+use tokio::time;
+
+use fswatcher::{FileSystemEvent, FileSystemWatcher, FileSystemWatcherBuilder};
+
+/// Time without any events after which the stream is considered quiescent.
+pub const QUIET_PERIOD: Duration = Duration::from_millis(200);
+
+/// Returns all events until no event has been received for `timeout` or until the stream ends.
+pub async fn collect_events<S>(stream: &mut S, timeout: Duration) -> Vec<FileSystemEvent>
+where
+    S: Stream<Item = FileSystemEvent> + Unpin,
+{
+    let mut events = Vec::new();
+    while let Ok(Some(event)) = time::timeout(timeout, stream.next()).await {
+        events.push(event);
+    }
+    events
+}
+
+/// Compares the events via their debug representation, as `FileSystemEvent` does not implement
+/// `PartialEq`.
+pub fn assert_events<T: Debug>(events: &[FileSystemEvent], expected: &[T]) {
+    assert_eq!(format!("{:?}", events), format!("{:?}", expected));
+}
+
+/// Temporary directory monitored by a watcher. The directory is removed when the fixture is
+/// dropped.
+pub struct Fixture {
+    pub dir: TempDir,
+    pub watcher: FileSystemWatcher,
+}
+
+impl Fixture {
+    /// Creates a watcher with the default configuration for an empty temporary directory.
+    pub async fn new() -> Fixture {
+        Fixture::with_builder(|builder| builder).await
+    }
+
+    /// Creates a watcher for an empty temporary directory, `configure` can modify the
+    /// configuration of the watcher.
+    ///
+    /// The events of the initial scan are discarded, so that the next events are caused by the
+    /// changes made by the test.
+    pub async fn with_builder<F>(configure: F) -> Fixture
+    where
+        F: FnOnce(FileSystemWatcherBuilder) -> FileSystemWatcherBuilder,
+    {
+        Fixture::with_contents(&[], &[], configure).await
+    }
+
+    /// Same as `with_builder()`, but first creates the specified directories and (empty) files.
+    pub async fn with_contents<F>(directories: &[&str], files: &[&str], configure: F) -> Fixture
+    where
+        F: FnOnce(FileSystemWatcherBuilder) -> FileSystemWatcherBuilder,
+    {
+        let dir = tempfile::tempdir().unwrap();
+        for directory in directories {
+            std::fs::create_dir_all(dir.path().join(directory)).unwrap();
+        }
+        for file in files {
+            std::fs::write(dir.path().join(file), "").unwrap();
+        }
+        let mut watcher = configure(FileSystemWatcher::builder(dir.path().as_os_str()))
+            .build()
+            .unwrap();
+        collect_events(&mut watcher, QUIET_PERIOD).await;
+        Fixture { dir, watcher }
+    }
+
+    /// Returns the root directory.
+    pub fn root(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Returns the absolute path of an entry in the temporary directory.
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    /// Returns the events generated since the last call, see `collect_events()`.
+    pub async fn events(&mut self) -> Vec<FileSystemEvent> {
+        collect_events(&mut self.watcher, QUIET_PERIOD).await
+    }
+}
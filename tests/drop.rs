@@ -0,0 +1,46 @@
+//! Checks that dropped watchers do not leak inotify instances.
+//!
+//! The test runs in a separate process, as other tests would create inotify instances
+//! concurrently.
+
+#![cfg(target_os = "linux")]
+
+use std::fs;
+use std::path::Path;
+
+use futures::StreamExt;
+
+use fswatcher::FileSystemWatcher;
+
+/// Returns the number of inotify file descriptors of the current process.
+fn inotify_fd_count() -> usize {
+    fs::read_dir("/proc/self/fd")
+        .unwrap()
+        .filter_map(|entry| fs::read_link(entry.ok()?.path()).ok())
+        .filter(|target| target == Path::new("anon_inode:inotify"))
+        .count()
+}
+
+#[tokio::test]
+async fn dropped_watchers_release_their_file_descriptors() {
+    let dir = tempfile::tempdir().unwrap();
+    for subdir in &["a/b", "c/d", "e"] {
+        fs::create_dir_all(dir.path().join(subdir)).unwrap();
+    }
+    let initial = inotify_fd_count();
+
+    for i in 0..200 {
+        let mut watcher = FileSystemWatcher::new(dir.path().as_os_str()).unwrap();
+        // Drop some of the watchers in the middle of the initial scan, after removing watches
+        // which are still pending in the inotify queue.
+        for _ in 0..i % 4 {
+            watcher.next().await;
+        }
+        if i % 2 == 1 {
+            watcher.unwatch(dir.path().join("a").as_os_str());
+        }
+        drop(watcher);
+    }
+
+    assert!(inotify_fd_count() <= initial);
+}
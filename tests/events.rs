@@ -0,0 +1,105 @@
+//! Integration tests which modify a temporary directory and check the generated events.
+//!
+//! The exact sequence of events depends on the backend, so the tests only run on Linux.
+
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::fs;
+use std::io::Write;
+
+use fswatcher::{FileSystemEvent, StopReason};
+
+use common::{assert_events, Fixture};
+
+#[tokio::test]
+async fn file_lifecycle() {
+    let mut fixture = Fixture::new().await;
+    let file = fixture.path("a");
+
+    fs::write(&file, "a").unwrap();
+    assert_events(
+        &fixture.events().await,
+        &[
+            FileSystemEvent::FileCreated(file.clone().into(), None),
+            FileSystemEvent::FileModified(file.clone().into(), None),
+        ],
+    );
+
+    // Truncating the file would generate a second modification.
+    fs::OpenOptions::new()
+        .append(true)
+        .open(&file)
+        .unwrap()
+        .write_all(b"b")
+        .unwrap();
+    assert_events(
+        &fixture.events().await,
+        &[FileSystemEvent::FileModified(file.clone().into(), None)],
+    );
+
+    fs::remove_file(&file).unwrap();
+    assert_events(
+        &fixture.events().await,
+        &[FileSystemEvent::FileRemoved(file.into())],
+    );
+}
+
+#[tokio::test]
+async fn files_are_moved() {
+    let mut fixture = Fixture::with_contents(&["sub"], &["a"], |builder| builder).await;
+
+    fs::rename(fixture.path("a"), fixture.path("sub/b")).unwrap();
+    assert_events(
+        &fixture.events().await,
+        &[FileSystemEvent::FileMoved(
+            fixture.path("a").into(),
+            fixture.path("sub/b").into(),
+        )],
+    );
+}
+
+#[tokio::test]
+async fn new_directories_are_watched() {
+    let mut fixture = Fixture::new().await;
+    let sub = fixture.path("sub");
+
+    fs::create_dir(&sub).unwrap();
+    assert_events(
+        &fixture.events().await,
+        &[
+            FileSystemEvent::DirectoryCreated(sub.clone().into()),
+            FileSystemEvent::DirectoryWatched(sub.clone().into()),
+        ],
+    );
+
+    let file = sub.join("file");
+    fs::write(&file, "").unwrap();
+    assert_events(
+        &fixture.events().await,
+        &[FileSystemEvent::FileCreated(file.into(), None)],
+    );
+
+    fs::remove_dir_all(&sub).unwrap();
+    assert_events(
+        &fixture.events().await,
+        &[
+            FileSystemEvent::FileRemoved(sub.join("file").into()),
+            FileSystemEvent::DirectoryRemoved(sub.into()),
+        ],
+    );
+}
+
+#[tokio::test]
+async fn removing_the_root_stops_the_watcher() {
+    let mut fixture = Fixture::with_contents(&["sub"], &[], |builder| builder).await;
+
+    fs::remove_dir_all(fixture.root()).unwrap();
+    let events = fixture.events().await;
+    assert_events(
+        &events[events.len() - 1..],
+        &[FileSystemEvent::Stopped(StopReason::DirectoryRemoved)],
+    );
+    assert!(!fixture.watcher.is_active());
+}